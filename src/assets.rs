@@ -0,0 +1,216 @@
+use anyhow::{Context, Result, bail, ensure};
+use prost::Message;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::proto::lq_config::ConfigTables;
+
+pub const LIQI_FILES: [&str; 2] = ["liqi.json", "liqi.desc"];
+pub const LQC_FILE: &str = "lqc.lqbin";
+
+/// Checks that `bytes` is a usable copy of the asset called `name`
+pub fn validate(name: &str, bytes: &[u8]) -> Result<()> {
+    match name {
+        "liqi.json" => {
+            let json: Value = serde_json::from_slice(bytes).context("liqi.json不是有效的JSON")?;
+            ensure!(json["nested"]["lq"].is_object(), "liqi.json中没有lq包");
+        }
+        "liqi.desc" => {
+            prost_reflect::DescriptorPool::decode(bytes).context("liqi.desc无法解析")?;
+        }
+        LQC_FILE => {
+            let tables = ConfigTables::decode(bytes).context("lqc.lqbin无法解析")?;
+            ensure!(!tables.datas.is_empty(), "lqc.lqbin中没有数据");
+        }
+        _ => bail!("未知的文件: {name}"),
+    }
+    Ok(())
+}
+
+/// Compares `bytes` with a digest in the `sha256:<hex>` form GitHub reports for release assets
+pub fn verify_digest(name: &str, bytes: &[u8], digest: &str) -> Result<()> {
+    let Some(expected) = digest.strip_prefix("sha256:") else {
+        warn!("不支持的校验值格式, 跳过校验: {digest}");
+        return Ok(());
+    };
+    let actual: String = Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    ensure!(
+        actual.eq_ignore_ascii_case(expected),
+        "{name}校验失败, 文件可能不完整"
+    );
+    Ok(())
+}
+
+/// Replaces a group of files in the config dir together.
+///
+/// Every file is validated and written next to its target first; the current files are kept
+/// as `.bak` so a bad update can be rolled back with [`rollback`]. Only the files the last commit
+/// replaced have a backup, so rolling back the whole group never mixes versions.
+pub struct Staging {
+    dir: PathBuf,
+    /// Files updated together, not all of them need to be staged
+    group: &'static [&'static str],
+    staged: Vec<&'static str>,
+}
+
+impl Staging {
+    pub fn new(dir: &Path, group: &'static [&'static str]) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            group,
+            staged: Vec::new(),
+        }
+    }
+
+    pub fn stage(&mut self, name: &'static str, bytes: &[u8]) -> Result<()> {
+        validate(name, bytes)?;
+        let tmp = self.dir.join(format!("{name}.tmp"));
+        write_synced(&tmp, bytes).with_context(|| format!("无法写入{}", tmp.display()))?;
+        self.staged.push(name);
+        Ok(())
+    }
+
+    /// Moves the staged files into place, restoring the ones already moved if any step fails.
+    ///
+    /// Returns the names of the replaced files, the ones to [`rollback`] if they turn out unusable.
+    pub fn commit(mut self) -> Result<Vec<&'static str>> {
+        let staged = std::mem::take(&mut self.staged);
+        for (i, name) in staged.iter().enumerate() {
+            if let Err(e) = self.replace(name) {
+                for done in &staged[..i] {
+                    restore(&self.dir, done);
+                }
+                for pending in &staged[i..] {
+                    let _ = std::fs::remove_file(self.dir.join(format!("{pending}.tmp")));
+                }
+                return Err(e.context(format!("无法替换{name}")));
+            }
+        }
+        // left by an earlier commit, they belong to another version than the files now in place
+        for name in self.group.iter().filter(|name| !staged.contains(name)) {
+            let _ = std::fs::remove_file(self.dir.join(format!("{name}.bak")));
+        }
+        Ok(staged)
+    }
+
+    fn replace(&self, name: &str) -> Result<()> {
+        let target = self.dir.join(name);
+        let backup = self.dir.join(format!("{name}.bak"));
+        if target.is_file() {
+            std::fs::copy(&target, backup)?;
+        } else if backup.is_file() {
+            // nothing to go back to
+            std::fs::remove_file(backup)?;
+        }
+        std::fs::rename(self.dir.join(format!("{name}.tmp")), target)?;
+        Ok(())
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        // staged but never committed
+        for name in &self.staged {
+            let _ = std::fs::remove_file(self.dir.join(format!("{name}.tmp")));
+        }
+    }
+}
+
+/// Puts the `.bak` copies of `names` back, returns whether there was anything to restore
+pub fn rollback(dir: &Path, names: &[&str]) -> bool {
+    let mut restored = false;
+    for name in names {
+        if dir.join(format!("{name}.bak")).is_file() {
+            restored |= restore(dir, name);
+        }
+    }
+    restored
+}
+
+fn restore(dir: &Path, name: &str) -> bool {
+    match std::fs::copy(dir.join(format!("{name}.bak")), dir.join(name)) {
+        Ok(_) => {
+            info!("已回滚{name}");
+            true
+        }
+        Err(e) => {
+            warn!("回滚{name}失败: {e}");
+            false
+        }
+    }
+}
+
+/// Writes `bytes` to a temporary file next to `path` and renames it over `path`
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    write_synced(&tmp, bytes).with_context(|| format!("无法写入{}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("无法写入{}", path.display()))
+}
+
+fn write_synced(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const JSON: &[u8] = br#"{"nested":{"lq":{}}}"#;
+    // an empty FileDescriptorSet
+    const DESC: &[u8] = b"";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("majsoul_max_rs-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn rollback_restores_only_what_the_last_commit_replaced() {
+        let dir = temp_dir("assets-rollback");
+        fs::write(dir.join("liqi.json"), "json 1").unwrap();
+        fs::write(dir.join("liqi.json.bak"), "json 0").unwrap();
+        fs::write(dir.join("liqi.desc"), "desc 1").unwrap();
+
+        let mut staging = Staging::new(&dir, &LIQI_FILES);
+        staging.stage("liqi.desc", DESC).unwrap();
+        assert_eq!(staging.commit().unwrap(), ["liqi.desc"]);
+        assert!(!dir.join("liqi.json.bak").exists());
+        assert_eq!(fs::read(dir.join("liqi.desc")).unwrap(), DESC);
+
+        assert!(rollback(&dir, &LIQI_FILES));
+        assert_eq!(fs::read_to_string(dir.join("liqi.json")).unwrap(), "json 1");
+        assert_eq!(fs::read_to_string(dir.join("liqi.desc")).unwrap(), "desc 1");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_commit_restores_replaced_files() {
+        let dir = temp_dir("assets-commit");
+        fs::write(dir.join("liqi.json"), "json 1").unwrap();
+        // a directory in the way makes replacing liqi.desc fail
+        fs::create_dir(dir.join("liqi.desc")).unwrap();
+
+        let mut staging = Staging::new(&dir, &LIQI_FILES);
+        staging.stage("liqi.json", JSON).unwrap();
+        staging.stage("liqi.desc", DESC).unwrap();
+        assert!(staging.commit().is_err());
+        assert_eq!(fs::read_to_string(dir.join("liqi.json")).unwrap(), "json 1");
+        assert!(!dir.join("liqi.json.tmp").exists());
+        assert!(!dir.join("liqi.desc.tmp").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
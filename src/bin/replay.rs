@@ -0,0 +1,38 @@
+use clap::Parser;
+use majsoul_max_rs::{
+    LevelFilter, ModSettings, Modder, Result, RwLock, Settings, init_trace, replay,
+};
+use std::path::PathBuf;
+
+/// 离线回放抓包文件, 不需要网络
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// 抓包文件, 位于配置目录的captures下
+    capture: PathBuf,
+    /// 配置目录, 默认为./liqi_config, 不存在时使用程序所在目录下的liqi_config
+    #[arg(long)]
+    config_dir: Option<PathBuf>,
+    /// 不经过Mod处理
+    #[arg(long)]
+    no_mod: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    init_trace(LevelFilter::INFO);
+    let args = Args::parse();
+
+    let settings = Settings::new(args.config_dir.as_deref())?;
+
+    let modder = if args.no_mod {
+        None
+    } else {
+        let mut mod_settings = ModSettings::new(&settings)?;
+        // never write settings.mod.json while replaying
+        mod_settings.set_dry_run(true);
+        Some(Modder::new(RwLock::new(mod_settings)).await?)
+    };
+
+    replay(&args.capture, &settings, modder.as_ref()).await
+}
@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use base64::prelude::*;
+use hudsucker::{
+    certificate_authority::RcgenAuthority,
+    rcgen::{
+        BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, Issuer, KeyPair,
+        KeyUsagePurpose,
+    },
+    rustls,
+};
+use rand::{Rng, rng};
+use std::path::Path;
+use tracing::{info, warn};
+
+const CA_KEY_FILE: &str = "ca.key";
+const CA_CERT_FILE: &str = "ca.cer";
+const CA_NAME: &str = "MajsoulMax-rs CA";
+
+/// Encoding of an exported certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CertFormat {
+    Pem,
+    Der,
+}
+
+/// The root certificate the proxy signs intercepted connections with
+#[derive(Debug, Clone)]
+pub struct CaCert {
+    pub cert_pem: String,
+    key_pem: String,
+}
+
+impl CaCert {
+    /// Loads the CA from the config dir, generating a unique one on first run.
+    ///
+    /// With `bundled` set the publicly known hudsucker pair compiled into the binary is used instead.
+    pub fn load(dir: &Path, bundled: bool) -> Result<Self> {
+        if bundled {
+            warn!("正在使用公开的内置证书, 任何人都可以用它解密你的流量");
+            return Ok(Self {
+                cert_pem: include_str!("./ca/hudsucker.cer").to_string(),
+                key_pem: include_str!("./ca/hudsucker.key").to_string(),
+            });
+        }
+        let key_path = dir.join(CA_KEY_FILE);
+        let cert_path = dir.join(CA_CERT_FILE);
+        if key_path.is_file() && cert_path.is_file() {
+            return Ok(Self {
+                cert_pem: std::fs::read_to_string(cert_path).context("无法读取ca.cer")?,
+                key_pem: std::fs::read_to_string(key_path).context("无法读取ca.key")?,
+            });
+        }
+
+        let ca = Self::generate()?;
+        write_private(&key_path, &ca.key_pem).context("无法写入ca.key")?;
+        std::fs::write(&cert_path, &ca.cert_pem).context("无法写入ca.cer")?;
+        info!("已生成新的根证书, 请安装并信任: {}", cert_path.display());
+        Ok(ca)
+    }
+
+    fn generate() -> Result<Self> {
+        let key_pair = KeyPair::generate().context("Failed to generate key pair")?;
+        let mut params = CertificateParams::default();
+        params.serial_number = Some(rng().random::<u64>().into());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+        let mut distinguished_name = DistinguishedName::new();
+        distinguished_name.push(DnType::CommonName, CA_NAME);
+        distinguished_name.push(DnType::OrganizationName, "MajsoulMax-rs");
+        params.distinguished_name = distinguished_name;
+        let cert = params
+            .self_signed(&key_pair)
+            .context("Failed to self-sign CA certificate")?;
+        Ok(Self {
+            cert_pem: cert.pem(),
+            key_pem: key_pair.serialize_pem(),
+        })
+    }
+
+    pub fn cert_der(&self) -> Result<Vec<u8>> {
+        let body: String = self
+            .cert_pem
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        BASE64_STANDARD
+            .decode(body.trim())
+            .context("Failed to decode CA certificate")
+    }
+
+    pub fn export(&self, path: &Path, format: CertFormat) -> Result<()> {
+        match format {
+            CertFormat::Pem => std::fs::write(path, &self.cert_pem)?,
+            CertFormat::Der => std::fs::write(path, self.cert_der()?)?,
+        }
+        Ok(())
+    }
+
+    pub fn authority(&self) -> Result<RcgenAuthority> {
+        let key_pair = KeyPair::from_pem(&self.key_pem).context("Failed to parse key pair")?;
+        let issuer = Issuer::from_ca_cert_pem(&self.cert_pem, key_pair)
+            .context("Failed to parse CA certificate")?;
+
+        let ca = RcgenAuthority::new(issuer, 1_000, rustls::crypto::aws_lc_rs::default_provider());
+        Ok(ca)
+    }
+}
+
+/// Writes the private key readable by the current user only where the platform allows it
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents.as_bytes())
+}
@@ -0,0 +1,196 @@
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hudsucker::{
+    Body,
+    hyper::{Method, Request, Response, StatusCode, header, http::uri::Scheme},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue, json};
+use std::net::SocketAddr;
+use tokio::{net::lookup_host, sync::RwLock};
+use tracing::info;
+
+use crate::{modder::Modder, settings::Settings};
+
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// The part of `Settings` exposed through the control API
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SettingsView<'a> {
+    helper_switch: bool,
+    mod_switch: bool,
+    capture_switch: bool,
+    api_url: &'a str,
+    send_method: &'a [String],
+    send_action: &'a [String],
+}
+
+impl<'a> From<&'a Settings> for SettingsView<'a> {
+    fn from(settings: &'a Settings) -> Self {
+        Self {
+            helper_switch: settings.helper_on(),
+            mod_switch: settings.mod_on(),
+            capture_switch: settings.capture_on(),
+            api_url: &settings.api_url,
+            send_method: &settings.send_method,
+            send_action: &settings.send_action,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct SettingsPatch {
+    helper_switch: Option<bool>,
+    mod_switch: Option<bool>,
+    capture_switch: Option<bool>,
+    api_url: Option<String>,
+    send_method: Option<Vec<String>>,
+    send_action: Option<Vec<String>>,
+}
+
+/// Serves `/api/settings` and `/api/mod` to clients on this machine.
+///
+/// Changes to settings only last for this run, like command line options,
+/// while changes to mod settings are written to settings.mod.json.
+pub async fn serve(
+    req: Request<Body>,
+    client: SocketAddr,
+    settings: &RwLock<Settings>,
+    modder: Option<&Modder>,
+) -> Response<Body> {
+    if !client.ip().to_canonical().is_loopback() {
+        return error_response(StatusCode::FORBIDDEN, "仅允许本机访问控制接口");
+    }
+    // proxied requests reach the API from this machine too, only local tools can read the token
+    if !authorized(&req, settings.read().await.control_token()) {
+        return error_response(StatusCode::UNAUTHORIZED, "控制接口令牌错误");
+    }
+    let res = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/api/settings") => {
+            Ok(json_response(&SettingsView::from(&*settings.read().await)))
+        }
+        (&Method::PATCH, "/api/settings") => patch_settings(req, settings).await,
+        (&Method::GET, "/api/mod") => match modder {
+            Some(modder) => Ok(json_response(&*modder.mod_settings().read().await)),
+            None => return error_response(StatusCode::NOT_FOUND, "mod未加载"),
+        },
+        (&Method::PATCH, "/api/mod") => match modder {
+            Some(modder) => patch_mod(req, modder).await,
+            None => return error_response(StatusCode::NOT_FOUND, "mod未加载"),
+        },
+        (_, "/api/settings" | "/api/mod") => {
+            return error_response(StatusCode::METHOD_NOT_ALLOWED, "不支持的请求方法");
+        }
+        _ => return error_response(StatusCode::NOT_FOUND, "未知的接口"),
+    };
+    res.unwrap_or_else(|e| error_response(StatusCode::BAD_REQUEST, &format!("{e:#}")))
+}
+
+fn authorized(req: &Request<Body>, token: &str) -> bool {
+    !token.is_empty()
+        && req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| given == token)
+}
+
+/// Whether a proxied request or tunnel is aimed back at the proxy listening on `listen`,
+/// which hudsucker would forward from this machine past the loopback check
+pub async fn targets_proxy(req: &Request<Body>, listen: SocketAddr) -> bool {
+    let Some(authority) = req.uri().authority() else {
+        return false;
+    };
+    let default_port = if req.uri().scheme() == Some(&Scheme::HTTPS) {
+        443
+    } else {
+        80
+    };
+    let port = authority.port_u16().unwrap_or(default_port);
+    if port != listen.port() {
+        return false;
+    }
+    // IPv6 literals keep their brackets in the authority
+    let host = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    match lookup_host((host, port)).await {
+        Ok(mut addrs) => addrs.any(|addr| {
+            let ip = addr.ip().to_canonical();
+            ip.is_loopback() || ip.is_unspecified() || ip == listen.ip()
+        }),
+        // hudsucker cannot connect either
+        Err(_) => false,
+    }
+}
+
+async fn patch_settings(req: Request<Body>, settings: &RwLock<Settings>) -> Result<Response<Body>> {
+    let patch: SettingsPatch = serde_json::from_slice(&read_body(req).await?)?;
+    // checked before anything is applied, so a rejected patch changes nothing
+    if let Some(api_url) = &patch.api_url {
+        let url = url::Url::parse(api_url).context("apiUrl无效")?;
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("apiUrl必须是http或https地址");
+        }
+    }
+    let mut settings = settings.write().await;
+    if let Some(helper) = patch.helper_switch {
+        settings.set_helper_on(helper);
+    }
+    if let Some(mod_switch) = patch.mod_switch {
+        settings.set_mod_on(mod_switch);
+    }
+    if let Some(capture) = patch.capture_switch {
+        settings.set_capture_on(capture);
+    }
+    if let Some(api_url) = patch.api_url {
+        settings.api_url = api_url;
+        settings.warn_api_url_ignored();
+    }
+    if let Some(send_method) = patch.send_method {
+        settings.set_send_method(send_method);
+    }
+    if let Some(send_action) = patch.send_action {
+        settings.set_send_action(send_action);
+    }
+    info!("已通过控制接口修改配置");
+    Ok(json_response(&SettingsView::from(&*settings)))
+}
+
+async fn patch_mod(req: Request<Body>, modder: &Modder) -> Result<Response<Body>> {
+    let patch: Map<String, JsonValue> = serde_json::from_slice(&read_body(req).await?)?;
+    let mut mod_settings = modder.mod_settings().write().await;
+    mod_settings.apply_patch(patch)?;
+    mod_settings.write();
+    info!("已通过控制接口修改Mod配置");
+    Ok(json_response(&*mod_settings))
+}
+
+async fn read_body(req: Request<Body>) -> Result<Bytes> {
+    let body = Limited::new(req.into_body(), MAX_BODY_SIZE)
+        .collect()
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+        .context("无法读取请求体")?;
+    Ok(body.to_bytes())
+}
+
+fn json_response(value: &impl Serialize) -> Response<Body> {
+    let body = serde_json::to_vec(value).expect("Failed to serialize response");
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(Full::new(Bytes::from(body))))
+        .expect("Failed to build control response")
+}
+
+pub fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    let mut res = json_response(&json!({ "error": message }));
+    *res.status_mut() = status;
+    res
+}
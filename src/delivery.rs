@@ -0,0 +1,417 @@
+use anyhow::Result;
+use serde_json::Value as JsonValue;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::Notify, time::sleep};
+use tracing::{info, warn};
+
+use crate::interceptor::BoxFuture;
+
+/// Payloads waiting for a helper, older ones are dropped and resynced once this is exceeded
+const MAX_QUEUED: usize = 1024;
+/// Payloads of the current round kept per game for resyncs
+pub(crate) const MAX_ROUND: usize = 2048;
+/// Games whose current round is kept, the oldest are forgotten first
+const MAX_GAMES: usize = 4;
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Somewhere helper payloads are delivered to
+pub trait Endpoint: Send + Sync {
+    /// Delivers one payload, an error means it should be retried later
+    fn send<'a>(&'a self, payload: &'a JsonValue) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Takes the payloads of one helper target
+pub trait Outlet: Send + Sync {
+    /// Queues `payload` of game `session`; `new_round` marks the start of a round with it
+    fn push(&self, session: u64, payload: JsonValue, new_round: bool);
+}
+
+/// Delivers helper payloads to one endpoint in order, one at a time.
+///
+/// When the endpoint fails, everything queued is dropped and once it is back the
+/// current round of each affected game is replayed from its start instead, so the
+/// helper ends up in the same state whether or not it was restarted in between.
+/// Payloads past the part of a round that is kept stay queued behind its replay.
+pub struct Delivery {
+    /// Names the endpoint in the log
+    label: String,
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<(u64, Arc<JsonValue>)>,
+    /// Payloads since the start of the current round, by game session
+    rounds: BTreeMap<u64, Vec<Arc<JsonValue>>>,
+    /// Games to replay, with the index of the next payload of their round to send
+    resync: HashMap<u64, usize>,
+}
+
+enum Next {
+    Queued(Arc<JsonValue>),
+    Replay(u64, usize, Arc<JsonValue>),
+}
+
+impl Outlet for Delivery {
+    fn push(&self, session: u64, payload: JsonValue, new_round: bool) {
+        let payload = Arc::new(payload);
+        let mut state = self.state.lock().expect("Delivery state poisoned");
+        let kept = state.record(session, payload.clone(), new_round);
+        if kept
+            && state
+                .rounds
+                .get(&session)
+                .is_some_and(|round| round.len() == MAX_ROUND)
+        {
+            warn!(
+                "[{}] 当前局助手消息已达{MAX_ROUND}条, 之后的消息将不会在重新同步时重发",
+                self.label
+            );
+        }
+        // a replay only covers what the round kept, the rest waits in the queue behind it
+        if !kept || !state.resync.contains_key(&session) {
+            if state.queue.len() >= MAX_QUEUED
+                && let Some((oldest, _)) = state.queue.front()
+            {
+                let oldest = *oldest;
+                warn!("[{}] 助手消息积压过多, 将重新同步当前局", self.label);
+                state.drop_queued(oldest);
+            }
+            // the replay covers it if this game was just dropped
+            if !kept || !state.resync.contains_key(&session) {
+                state.queue.push_back((session, payload));
+            }
+        }
+        drop(state);
+        self.notify.notify_one();
+    }
+}
+
+impl Delivery {
+    pub fn new(label: String) -> Self {
+        Self {
+            label,
+            state: Mutex::default(),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Sends queued payloads to `endpoint` forever, backing off while it fails
+    pub async fn run(&self, endpoint: &dyn Endpoint) {
+        let mut backoff = MIN_BACKOFF;
+        let mut down = false;
+        loop {
+            let next = self.next();
+            let Some(next) = next else {
+                self.notify.notified().await;
+                continue;
+            };
+            let payload = match &next {
+                Next::Queued(payload) | Next::Replay(_, _, payload) => payload,
+            };
+            if let Err(e) = endpoint.send(payload).await {
+                if !down {
+                    warn!(
+                        "[{}] 发送至助手失败, 恢复后将重新同步当前局: {e:#}",
+                        self.label
+                    );
+                    down = true;
+                }
+                self.state
+                    .lock()
+                    .expect("Delivery state poisoned")
+                    .mark_down();
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            }
+            if down {
+                info!("[{}] 助手已恢复, 正在重新同步当前局", self.label);
+                down = false;
+            }
+            backoff = MIN_BACKOFF;
+            let mut state = self.state.lock().expect("Delivery state poisoned");
+            match next {
+                Next::Queued(payload) => {
+                    if state
+                        .queue
+                        .front()
+                        .is_some_and(|(_, front)| Arc::ptr_eq(front, &payload))
+                    {
+                        state.queue.pop_front();
+                    }
+                }
+                Next::Replay(session, index, _) => {
+                    // the round may have restarted meanwhile
+                    if let Some(cursor) = state.resync.get_mut(&session)
+                        && *cursor == index
+                    {
+                        *cursor += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replays the current round of the latest game and of every queued one, for an endpoint
+    /// that lost what it was sent, such as a restarted helper process
+    pub fn resync(&self) {
+        let mut state = self.state.lock().expect("Delivery state poisoned");
+        state.mark_down();
+        if let Some(&latest) = state.rounds.keys().next_back() {
+            state.resync.entry(latest).or_insert(0);
+        }
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    fn next(&self) -> Option<Next> {
+        let mut state = self.state.lock().expect("Delivery state poisoned");
+        while let Some((&session, &cursor)) = state.resync.iter().next() {
+            match state
+                .rounds
+                .get(&session)
+                .and_then(|round| round.get(cursor))
+            {
+                Some(payload) => return Some(Next::Replay(session, cursor, payload.clone())),
+                // caught up, back to normal delivery
+                None => {
+                    state.resync.remove(&session);
+                }
+            }
+        }
+        let (_, payload) = state.queue.front()?;
+        Some(Next::Queued(payload.clone()))
+    }
+}
+
+impl State {
+    /// Keeps `payload` for replays, returns `false` if its round is full already
+    fn record(&mut self, session: u64, payload: Arc<JsonValue>, new_round: bool) -> bool {
+        if new_round {
+            self.rounds.insert(session, Vec::new());
+            if let Some(cursor) = self.resync.get_mut(&session) {
+                *cursor = 0;
+            }
+        }
+        let round = self.rounds.entry(session).or_default();
+        let kept = round.len() < MAX_ROUND;
+        if kept {
+            round.push(payload);
+        }
+        // session ids only grow
+        while self.rounds.len() > MAX_GAMES {
+            self.rounds.pop_first();
+        }
+        kept
+    }
+
+    /// Replaces the queued payloads of `session` with a replay of its round
+    fn drop_queued(&mut self, session: u64) {
+        self.queue.retain(|(queued, _)| *queued != session);
+        self.resync.insert(session, 0);
+    }
+
+    fn mark_down(&mut self) {
+        for (session, _) in &self.queue {
+            self.resync.insert(*session, 0);
+        }
+        let rounds = &self.rounds;
+        // payloads past a full round are not replayed, they stay queued behind the replay
+        self.queue.retain(|(session, payload)| {
+            rounds.get(session).is_some_and(|round| {
+                round.len() == MAX_ROUND && !round.iter().any(|kept| Arc::ptr_eq(kept, payload))
+            })
+        });
+        // start over, the helper may have lost what was already replayed
+        for cursor in self.resync.values_mut() {
+            *cursor = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::time::Instant;
+
+    /// Fails the first `failures` sends, then keeps what it is sent
+    #[derive(Default)]
+    struct Recorder {
+        failures: Mutex<usize>,
+        attempts: Mutex<Vec<Instant>>,
+        sent: Mutex<Vec<JsonValue>>,
+    }
+
+    impl Recorder {
+        fn failing(failures: usize) -> Self {
+            Self {
+                failures: Mutex::new(failures),
+                ..Default::default()
+            }
+        }
+
+        fn sent(&self) -> Vec<JsonValue> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    impl Endpoint for Recorder {
+        fn send<'a>(&'a self, payload: &'a JsonValue) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move {
+                self.attempts.lock().unwrap().push(Instant::now());
+                let mut failures = self.failures.lock().unwrap();
+                if *failures > 0 {
+                    *failures -= 1;
+                    anyhow::bail!("down");
+                }
+                self.sent.lock().unwrap().push(payload.clone());
+                Ok(())
+            })
+        }
+    }
+
+    fn start(delivery: &Arc<Delivery>, endpoint: &Arc<Recorder>) -> tokio::task::JoinHandle<()> {
+        let (delivery, endpoint) = (delivery.clone(), endpoint.clone());
+        tokio::spawn(async move { delivery.run(&*endpoint).await })
+    }
+
+    fn round(state: &State, session: u64) -> Vec<JsonValue> {
+        state.rounds[&session]
+            .iter()
+            .map(|p| (**p).clone())
+            .collect()
+    }
+
+    #[test]
+    fn new_round_replaces_kept_round() {
+        let mut state = State::default();
+        for i in 0..3 {
+            state.record(1, Arc::new(json!(i)), i == 0);
+        }
+        state.resync.insert(1, 2);
+        state.record(1, Arc::new(json!("next")), true);
+        assert_eq!(round(&state, 1), [json!("next")]);
+        // a replay in progress starts over with the new round
+        assert_eq!(state.resync[&1], 0);
+    }
+
+    #[test]
+    fn overflow_coalesces_into_round_replay() {
+        let delivery = Delivery::new("test".to_string());
+        delivery.push(1, json!("start"), true);
+        for i in 1..=MAX_QUEUED {
+            delivery.push(1, json!(i), false);
+        }
+        let state = delivery.state.lock().unwrap();
+        assert!(state.queue.is_empty());
+        assert_eq!(state.resync[&1], 0);
+        assert_eq!(state.rounds[&1].len(), MAX_QUEUED + 1);
+        drop(state);
+        // later payloads of the game are covered by the replay instead of queued
+        delivery.push(1, json!("later"), false);
+        assert!(delivery.state.lock().unwrap().queue.is_empty());
+        match delivery.next() {
+            Some(Next::Replay(1, 0, payload)) => assert_eq!(*payload, json!("start")),
+            _ => panic!("expected a replay from the round start"),
+        }
+    }
+
+    #[test]
+    fn forgets_oldest_games_and_caps_rounds() {
+        let mut state = State::default();
+        for session in 1..=MAX_GAMES as u64 + 2 {
+            state.record(session, Arc::new(json!(session)), true);
+        }
+        assert_eq!(state.rounds.len(), MAX_GAMES);
+        assert_eq!(state.rounds.keys().next(), Some(&3));
+
+        let session = MAX_GAMES as u64 + 2;
+        for i in 0..MAX_ROUND + 10 {
+            state.record(session, Arc::new(json!(i)), false);
+        }
+        assert_eq!(state.rounds[&session].len(), MAX_ROUND);
+        // the start of the round is what matters for a replay
+        assert_eq!(*state.rounds[&session][0], json!(session));
+    }
+
+    #[test]
+    fn queues_what_a_full_round_cannot_replay() {
+        let delivery = Delivery::new("test".to_string());
+        delivery.push(1, json!("start"), true);
+        delivery.state.lock().unwrap().mark_down();
+        for i in 1..MAX_ROUND {
+            delivery.push(1, json!(i), false);
+        }
+        assert!(delivery.state.lock().unwrap().queue.is_empty());
+        delivery.push(1, json!("over"), false);
+        let state = delivery.state.lock().unwrap();
+        assert_eq!(state.rounds[&1].len(), MAX_ROUND);
+        assert_eq!(state.queue.len(), 1);
+        drop(state);
+        // an outage does not lose it either
+        delivery.state.lock().unwrap().mark_down();
+        assert_eq!(delivery.state.lock().unwrap().queue.len(), 1);
+        // sent once the replay of the kept part is done
+        delivery.state.lock().unwrap().resync.insert(1, MAX_ROUND);
+        match delivery.next() {
+            Some(Next::Queued(payload)) => assert_eq!(*payload, json!("over")),
+            _ => panic!("expected the payload past the round limit"),
+        }
+    }
+
+    #[test]
+    fn mark_down_resyncs_every_queued_game() {
+        let delivery = Delivery::new("test".to_string());
+        delivery.push(1, json!("a"), true);
+        delivery.push(2, json!("b"), true);
+        delivery.push(1, json!("c"), false);
+        let mut state = delivery.state.lock().unwrap();
+        state.resync.insert(3, 5);
+        state.mark_down();
+        assert!(state.queue.is_empty());
+        assert_eq!(state.resync, HashMap::from([(1, 0), (2, 0), (3, 0)]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_round_once_endpoint_recovers() {
+        let delivery = Arc::new(Delivery::new("test".to_string()));
+        let endpoint = Arc::new(Recorder::failing(3));
+        delivery.push(1, json!("old"), true);
+        delivery.push(1, json!("start"), true);
+        delivery.push(1, json!(1), false);
+        let task = start(&delivery, &endpoint);
+        sleep(Duration::from_secs(10)).await;
+        delivery.push(1, json!(2), false);
+        sleep(Duration::from_secs(1)).await;
+        task.abort();
+        // the failed payloads come once, as part of the replay
+        assert_eq!(endpoint.sent(), [json!("start"), json!(1), json!(2)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_doubles_up_to_limit() {
+        let delivery = Arc::new(Delivery::new("test".to_string()));
+        let endpoint = Arc::new(Recorder::failing(usize::MAX));
+        delivery.push(1, json!("start"), true);
+        let task = start(&delivery, &endpoint);
+        sleep(Duration::from_secs(130)).await;
+        task.abort();
+        let attempts = endpoint.attempts.lock().unwrap();
+        let gaps: Vec<u64> = attempts
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).as_millis() as u64)
+            .collect();
+        assert_eq!(
+            gaps,
+            [500, 1000, 2000, 4000, 8000, 16000, 30000, 30000, 30000]
+        );
+    }
+}
@@ -1,82 +1,293 @@
 use anyhow::Result;
+use bytes::Bytes;
 use hudsucker::{
     Body, HttpContext, RequestOrResponse,
     futures::{Sink, SinkExt, Stream, StreamExt},
-    hyper::{Request, Response, StatusCode},
-    tokio_tungstenite::tungstenite::{self, Message},
+    hyper::{Method, Request, Response, StatusCode, Uri, header},
+    hyper_util::rt::TokioIo,
+    tokio_tungstenite::{
+        WebSocketStream, client_async_tls_with_config,
+        tungstenite::{self, Message, handshake::derive_accept_key, protocol::Role},
+    },
     *,
 };
-use std::sync::Arc;
-use tokio::sync::{RwLock, mpsc::Sender};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::sync::RwLock;
 use tracing::*;
+use url::Url;
 
 use crate::{
+    cert::CaCert,
+    control,
+    interceptor::{Direction, Frame, Interceptor, Verdict},
     modder::Modder,
-    parser::{LiqiMessage, Parser},
+    parser::Parser,
+    recorder::Recorder,
+    session::{Injected, Session, SessionKey, Sessions},
     settings::Settings,
+    setup,
+    upstream::{self, Upstream},
 };
 
 #[derive(Clone)]
 pub struct Handler {
-    sender: Option<Sender<(LiqiMessage, char)>>,
+    interceptors: Arc<[Arc<dyn Interceptor>]>,
+    recorder: Recorder,
+    settings: Arc<RwLock<Settings>>,
     modder: Option<Arc<Modder>>,
-    inject_msg: Option<Message>,
-    parser: Arc<RwLock<Parser>>,
+    ca: Arc<CaCert>,
+    sessions: Sessions,
+    upstream: Upstream,
+    session: Option<Arc<Session>>,
 }
 
 impl Handler {
     pub fn new(
-        sender: Option<Sender<(LiqiMessage, char)>>,
+        interceptors: Vec<Arc<dyn Interceptor>>,
+        recorder: Recorder,
+        settings: Arc<RwLock<Settings>>,
         modder: Option<Arc<Modder>>,
-        settings: &'static Settings,
+        ca: CaCert,
+        sessions: Sessions,
+        upstream: Upstream,
     ) -> Self {
         Self {
-            sender,
+            interceptors: interceptors.into(),
+            recorder,
+            settings,
             modder,
-            inject_msg: None,
-            parser: Arc::new(RwLock::new(Parser::new(
-                &settings.proto_json,
-                &settings.desc,
-            ))),
+            ca: Arc::new(ca),
+            sessions,
+            upstream,
+            session: None,
         }
     }
 }
 
 impl HttpHandler for Handler {
-    async fn handle_request(
-        &mut self,
-        _ctx: &HttpContext,
-        req: Request<Body>,
-    ) -> RequestOrResponse {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        let direct = req.uri().authority().is_none();
         if req.uri().path() == "/ping" {
             Response::builder()
                 .status(StatusCode::OK)
                 .body(Body::from("pong"))
                 .expect("Failed to build ping response")
                 .into()
+        } else if direct && req.uri().path().starts_with("/api/") {
+            control::serve(req, ctx.client_addr, &self.settings, self.modder.as_deref())
+                .await
+                .into()
+        } else if self.targets_proxy(&req).await {
+            control::error_response(StatusCode::FORBIDDEN, "不允许经代理访问代理自身").into()
+        } else if let Some(res) = setup::serve(&req, &*self.settings.read().await, &self.ca) {
+            res.into()
+        } else if let Some(proxy) = self.takes_upstream(&req).await {
+            // hudsucker would connect to these directly
+            if req.method() == Method::CONNECT {
+                self.upstream.tunnel(req, proxy).into()
+            } else {
+                self.upgrade_websocket(ctx.client_addr, req, proxy).into()
+            }
         } else {
             req.into()
         }
     }
+
+    async fn should_intercept(&mut self, _ctx: &HttpContext, req: &Request<Body>) -> bool {
+        let host = req.uri().host().unwrap_or_default();
+        let intercept = self.settings.read().await.is_intercept_host(host);
+        if !intercept {
+            debug!("Tunnelling {host} without interception");
+        }
+        intercept
+    }
 }
 
 impl WebSocketHandler for Handler {
     async fn handle_websocket(
-        mut self,
+        self,
         ctx: WebSocketContext,
+        stream: impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin + Send + 'static,
+        sink: impl Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
+    ) {
+        let direction = match ctx {
+            WebSocketContext::ServerToClient { .. } => Direction::ServerToClient,
+            WebSocketContext::ClientToServer { .. } => Direction::ClientToServer,
+        };
+        self.forward(direction, SessionKey::from(&ctx), stream, sink)
+            .await;
+    }
+}
+
+impl Handler {
+    /// Whether `req` is proxied or tunnelled back to this proxy
+    async fn targets_proxy(&self, req: &Request<Body>) -> bool {
+        let listen = self.settings.read().await.proxy_addr.parse();
+        match listen {
+            Ok(listen) => control::targets_proxy(req, listen).await,
+            Err(_) => false,
+        }
+    }
+
+    /// Upstream proxy for a tunnel not to be intercepted or a WebSocket upgrade, if one is configured
+    async fn takes_upstream(&self, req: &Request<Body>) -> Option<Url> {
+        let host = req.uri().host()?;
+        let settings = self.settings.read().await;
+        let takes = if req.method() == Method::CONNECT {
+            !settings.is_intercept_host(host)
+        } else {
+            is_websocket_upgrade(req)
+        };
+        if !takes {
+            return None;
+        }
+        settings.upstream_proxy(host).cloned()
+    }
+
+    /// Accepts a WebSocket upgrade and connects to the server through `proxy`
+    fn upgrade_websocket(
+        &self,
+        client: SocketAddr,
+        mut req: Request<Body>,
+        proxy: Url,
+    ) -> Response<Body> {
+        let (Some(key), Some((host, port))) = (
+            req.headers().get(header::SEC_WEBSOCKET_KEY),
+            upstream::host_port(req.uri()),
+        ) else {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .expect("Failed to build response");
+        };
+        let res = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(
+                header::SEC_WEBSOCKET_ACCEPT,
+                derive_accept_key(key.as_bytes()),
+            )
+            .body(Body::empty())
+            .expect("Failed to build upgrade response");
+
+        let upgrade = hudsucker::hyper::upgrade::on(&mut req);
+        let (mut parts, _) = req.into_parts();
+        let mut uri = parts.uri.into_parts();
+        uri.scheme = Some(
+            if uri
+                .scheme
+                .as_ref()
+                .is_some_and(|scheme| scheme.as_str() == "http")
+            {
+                "ws"
+            } else {
+                "wss"
+            }
+            .parse()
+            .expect("Failed to convert scheme"),
+        );
+        let Ok(uri) = Uri::from_parts(uri) else {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .expect("Failed to build response");
+        };
+        parts.uri = uri.clone();
+        let server_req = Request::from_parts(parts, ());
+        let handler = self.clone();
+        tokio::spawn(async move {
+            let client_socket = match upgrade.await {
+                Ok(upgraded) => {
+                    WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None)
+                        .await
+                }
+                Err(e) => {
+                    error!("Failed to upgrade to WebSocket: {e}");
+                    return;
+                }
+            };
+            let stream = match upstream::connect_via(Some(&proxy), &host, port).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Failed to connect to {host}:{port} through {proxy}: {e}");
+                    return;
+                }
+            };
+            let connector = handler.upstream.websocket_connector();
+            let server_socket =
+                match client_async_tls_with_config(server_req, stream, None, Some(connector)).await
+                {
+                    Ok((server_socket, _)) => server_socket,
+                    Err(e) => {
+                        error!("Failed to handle WebSocket: {e}");
+                        return;
+                    }
+                };
+            let (server_sink, server_stream) = server_socket.split();
+            let (client_sink, client_stream) = client_socket.split();
+            let key = SessionKey {
+                client,
+                server: uri,
+            };
+            tokio::spawn(handler.clone().forward(
+                Direction::ServerToClient,
+                key.clone(),
+                server_stream,
+                client_sink,
+            ));
+            tokio::spawn(handler.forward(
+                Direction::ClientToServer,
+                key,
+                client_stream,
+                server_sink,
+            ));
+        });
+        res
+    }
+
+    /// Relays one direction of a WebSocket session, running every frame through the interceptors
+    async fn forward(
+        mut self,
+        direction: Direction,
+        key: SessionKey,
         mut stream: impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin + Send + 'static,
         mut sink: impl Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
     ) {
-        if let WebSocketContext::ServerToClient { .. } = ctx
-            && let Some(msg) = self.inject_msg.take()
-            && let Err(e) = sink.send(msg).await
-        {
-            error!("Failed to send injected message: {e}");
+        let host = key.server.host().unwrap_or_default();
+        if !self.settings.read().await.is_intercept_host(host) {
+            // plain ws:// is never tunnelled, so the allowlist is applied here
+            debug!("Relaying {direction} {host} without interception");
+            if let Err(e) = stream.forward(sink).await {
+                error!("WebSocket relay error: {e}");
+            }
+            return;
         }
-        while let Some(message) = stream.next().await {
+        let desc = self.settings.read().await.desc.clone();
+        let session = self.sessions.open(key.clone(), || Parser::new(desc)).await;
+        self.session = Some(session.clone());
+        let mut injected = match direction {
+            Direction::ServerToClient => session.take_injected().await,
+            Direction::ClientToServer => None,
+        };
+        loop {
+            // injected frames queued so far go out before the next real server frame
+            let message = tokio::select! {
+                biased;
+                Some(msg) = next_injected(&mut injected) => {
+                    if let Err(e) = sink.send(Message::Binary(msg)).await {
+                        error!("Failed to send injected message: {e}");
+                    }
+                    continue;
+                }
+                message = stream.next() => message,
+            };
+            let Some(message) = message else {
+                break;
+            };
             match message {
                 Ok(message) => {
-                    let Some(message) = self.handle_message(&ctx, message).await else {
+                    let Some(message) = self.process(direction, &key.server, message).await else {
                         continue;
                     };
 
@@ -99,49 +310,73 @@ impl WebSocketHandler for Handler {
                 }
             }
         }
+        self.sessions.close(&key, &session).await;
     }
 
-    async fn handle_message(&mut self, _ctx: &WebSocketContext, msg: Message) -> Option<Message> {
-        let (direction_char, uri) = match _ctx {
-            WebSocketContext::ServerToClient { src, .. } => ('\u{2193}', src),
-            WebSocketContext::ClientToServer { dst, .. } => ('\u{2191}', dst),
-        };
-
+    async fn process(&mut self, direction: Direction, uri: &Uri, msg: Message) -> Option<Message> {
         if uri.path() == "/ob" {
             // ignore ob messages
             return Some(msg);
         }
 
-        debug!("{direction_char} {uri}");
+        debug!("{direction} {uri}");
 
         let Message::Binary(buf) = msg else {
             return Some(msg);
         };
 
-        let mut parser = self.parser.write().await;
-        let Ok(parsed) = parser.parse(buf.clone()) else {
-            error!("Failed to parse message");
+        let Some(ref session) = self.session else {
             return Some(Message::Binary(buf));
         };
+        let mut parser = session.parser.lock().await;
+        let parsed = parser.parse(buf.clone());
         drop(parser);
-
-        let method_name = parsed.method_name.clone();
-        if let Some(tx) = &self.sender
-            && let Err(e) = tx.send((parsed, direction_char)).await
-        {
-            error!("Failed to send message to channel: {e}");
+        if self.settings.read().await.capture_on() {
+            self.recorder
+                .record(session.id, direction, &buf, parsed.as_ref().ok());
         }
-        let Some(ref modder) = self.modder else {
+        let Ok(parsed) = parsed else {
+            error!("Failed to parse message");
             return Some(Message::Binary(buf));
         };
-        let parser = self.parser.read().await;
-        let res = modder
-            .modify(buf, direction_char == '\u{2191}', method_name)
-            .await;
-        drop(parser);
-        if let Some(inj) = res.inject_msg {
-            self.inject_msg = Some(Message::Binary(inj));
+
+        let injector = session.injector();
+        let mut buf = buf;
+        for interceptor in self.interceptors.iter() {
+            let frame = Frame {
+                session_id: session.id,
+                direction,
+                method_name: &parsed.method_name,
+                message: &parsed,
+                raw: &buf,
+                injector: &injector,
+            };
+            match interceptor.intercept(frame).await {
+                Verdict::Pass => (),
+                Verdict::Modify(modified) => buf = modified,
+                Verdict::Drop => return None,
+            }
         }
-        res.msg.map(Message::Binary)
+        Some(Message::Binary(buf))
+    }
+}
+
+fn is_websocket_upgrade(req: &Request<Body>) -> bool {
+    let has_token = |name, token: &str| {
+        req.headers().get_all(name).iter().any(|value| {
+            value.to_str().is_ok_and(|value| {
+                value
+                    .split(',')
+                    .any(|v| v.trim().eq_ignore_ascii_case(token))
+            })
+        })
+    };
+    has_token(header::CONNECTION, "upgrade") && has_token(header::UPGRADE, "websocket")
+}
+
+async fn next_injected(injected: &mut Option<Injected>) -> Option<Bytes> {
+    match injected {
+        Some(injected) => injected.recv().await,
+        None => std::future::pending().await,
     }
 }
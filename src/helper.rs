@@ -1,15 +1,30 @@
 use crate::{
     ARBITRARY_MD5,
+    delivery::{Delivery, Endpoint, Outlet},
+    interceptor::{BoxFuture, Frame, Interceptor, Verdict},
     parser::{LiqiMessage, decode_action},
-    settings::Settings,
+    push::PushServer,
+    settings::{HelperQueue, HelperTarget, OverflowPolicy, Settings, TargetKind},
+    subprocess::ProcessEndpoint,
 };
-use anyhow::{Context, Result};
-use reqwest::Client;
+use anyhow::{Context, Result, bail};
+use reqwest::{Certificate, Client};
 use serde::Serialize;
 use serde_json::{Map, Value as JsonValue, json};
-use std::{future::Future, sync::LazyLock};
-use tokio::{spawn, sync::mpsc::Receiver, time::sleep};
-use tracing::{debug, error, info};
+use std::{
+    collections::{HashSet, VecDeque},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{Notify, RwLock},
+    task::JoinHandle,
+    time::interval,
+};
+use tracing::{debug, error, info, warn};
+use url::Url;
 
 #[derive(Serialize, Debug)]
 struct Action {
@@ -17,44 +32,467 @@ struct Action {
     pub data: JsonValue,
 }
 
-pub async fn helper_worker(mut receiver: Receiver<(LiqiMessage, char)>, settings: &Settings) {
-    loop {
-        let (parsed, direction_char) = match receiver.recv().await {
-            Some((b, c)) => (b, c),
-            None => {
-                error!("Failed to receive message from channel, retrying...");
-                sleep(std::time::Duration::from_secs(1)).await;
+/// Built-in interceptor feeding every decoded server frame to the helper worker
+pub struct HelperForwarder {
+    inbox: Arc<Inbox>,
+}
+
+impl HelperForwarder {
+    pub fn new(inbox: Arc<Inbox>) -> Self {
+        Self { inbox }
+    }
+}
+
+impl Interceptor for HelperForwarder {
+    fn intercept<'a>(&'a self, frame: Frame<'a>) -> BoxFuture<'a, Verdict> {
+        Box::pin(async move {
+            debug!(
+                "Method: {}, {}, {:?}, {}",
+                frame.direction,
+                frame.message.id,
+                frame.message.msg_type,
+                frame.message.method_name
+            );
+            // the helper only follows the server
+            if !frame.direction.from_client() {
+                self.inbox.push(frame.session_id, frame.message.clone());
+            }
+            Verdict::Pass
+        })
+    }
+}
+
+/// Frames waiting for the helper worker.
+///
+/// Pushing never waits, so a slow helper cannot hold up the game; once `capacity`
+/// frames are queued the configured [`OverflowPolicy`] decides what is dropped.
+pub struct Inbox {
+    state: Mutex<InboxState>,
+    notify: Notify,
+    capacity: usize,
+    overflow: OverflowPolicy,
+}
+
+#[derive(Default)]
+struct InboxState {
+    queue: VecDeque<(u64, LiqiMessage)>,
+    /// Set once the queue runs high, until the worker catches up
+    lagging: bool,
+    /// Frames dropped since the queue last ran high
+    dropped: u64,
+    dropped_total: u64,
+}
+
+impl Inbox {
+    pub fn new(config: &HelperQueue) -> Self {
+        Self {
+            state: Mutex::default(),
+            notify: Notify::new(),
+            capacity: config.capacity.max(1),
+            overflow: config.overflow,
+        }
+    }
+
+    /// Queues a server frame of game `session`
+    pub fn push(&self, session: u64, message: LiqiMessage) {
+        let mut state = self.state.lock().expect("Inbox state poisoned");
+        let dropped = if state.queue.len() < self.capacity {
+            state.queue.push_back((session, message));
+            0
+        } else {
+            match self.overflow {
+                OverflowPolicy::DropNewest => 1,
+                OverflowPolicy::DropOldest => {
+                    state.queue.pop_front();
+                    state.queue.push_back((session, message));
+                    1
+                }
+                OverflowPolicy::Coalesce => {
+                    state.queue.push_back((session, message));
+                    let mut dropped = state.coalesce();
+                    if dropped == 0 {
+                        state.queue.pop_front();
+                        dropped = 1;
+                    }
+                    dropped
+                }
+            }
+        };
+        let queued = state.queue.len();
+        if !state.lagging && queued * 4 >= self.capacity * 3 {
+            state.lagging = true;
+            warn!("助手处理不及, 已积压{queued}条消息");
+        }
+        if dropped > 0 {
+            if state.dropped == 0 {
+                warn!("助手消息队列已满, 开始丢弃消息");
+            }
+            state.dropped += dropped;
+            state.dropped_total += dropped;
+        }
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    /// Waits for the next frame
+    pub async fn recv(&self) -> (u64, LiqiMessage) {
+        loop {
+            if let Some(received) = self.pop() {
+                return received;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    fn pop(&self) -> Option<(u64, LiqiMessage)> {
+        let mut state = self.state.lock().expect("Inbox state poisoned");
+        let received = state.queue.pop_front()?;
+        if state.lagging && state.queue.is_empty() {
+            state.lagging = false;
+            if state.dropped > 0 {
+                warn!(
+                    "助手已追上, 期间丢弃{}条消息, 累计丢弃{}条",
+                    state.dropped, state.dropped_total
+                );
+                state.dropped = 0;
+            } else {
+                info!("助手已追上");
+            }
+        }
+        Some(received)
+    }
+}
+
+impl InboxState {
+    /// Drops frames followed by a round start of the same game, returning how many
+    fn coalesce(&mut self) -> u64 {
+        let mut restarted = HashSet::new();
+        let mut kept = VecDeque::with_capacity(self.queue.len());
+        let mut dropped = 0;
+        // newest first, so each game's latest round start is seen before what it supersedes
+        for (session, message) in self.queue.drain(..).rev() {
+            if restarted.contains(&session) {
+                dropped += 1;
                 continue;
             }
+            if starts_round(&message) {
+                restarted.insert(session);
+            }
+            kept.push_front((session, message));
+        }
+        self.queue = kept;
+        dropped
+    }
+}
+
+/// Posts payloads to the url of an HTTP helper target
+struct HttpEndpoint {
+    client: Client,
+    url: Url,
+}
+
+impl HttpEndpoint {
+    fn new(target: &HelperTarget, dir: &Path) -> Result<Self> {
+        let mut builder = reqwest::ClientBuilder::new()
+            .danger_accept_invalid_certs(!target.tls.verify)
+            .timeout(Duration::from_secs(10));
+        if let Some(ca_cert) = &target.tls.ca_cert {
+            let path = dir.join(ca_cert);
+            let pem =
+                std::fs::read(&path).with_context(|| format!("无法读取{}", path.display()))?;
+            builder = builder.add_root_certificate(
+                Certificate::from_pem(&pem)
+                    .with_context(|| format!("无法解析证书{}", path.display()))?,
+            );
+        }
+        Ok(Self {
+            client: builder.build().context("Failed to create reqwest client")?,
+            url: target.url.clone().context("No url for http target")?,
+        })
+    }
+}
+
+impl Endpoint for HttpEndpoint {
+    fn send<'a>(&'a self, payload: &'a JsonValue) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            // no verification of the response body
+            let res = self
+                .client
+                .post(self.url.clone())
+                .json(payload)
+                .send()
+                .await?;
+            let status = res.status();
+            if status.is_server_error() {
+                bail!("小助手返回{status}");
+            }
+            if status.is_success() {
+                info!("请求小助手已接收");
+            } else {
+                // resending would not help
+                warn!("小助手拒绝了消息: {status}, {}", self.url);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Frames a helper target takes, the global `sendMethod` / `sendAction` where it sets none
+#[derive(Default)]
+pub struct Filter {
+    methods: Option<HashSet<String>>,
+    actions: Option<HashSet<String>>,
+}
+
+impl Filter {
+    fn new(target: &HelperTarget) -> Self {
+        Self {
+            methods: target
+                .send_method
+                .as_ref()
+                .map(|methods| methods.iter().cloned().collect()),
+            actions: target
+                .send_action
+                .as_ref()
+                .map(|actions| actions.iter().cloned().collect()),
+        }
+    }
+
+    fn is_method(&self, settings: &Settings, method: &str) -> bool {
+        match &self.methods {
+            Some(methods) => methods.contains(method),
+            None => settings.is_method(method),
+        }
+    }
+
+    fn is_action(&self, settings: &Settings, action: &str) -> bool {
+        match &self.actions {
+            Some(actions) => actions.contains(action),
+            None => settings.is_action(action),
+        }
+    }
+}
+
+/// A helper target being delivered to, stopped when dropped
+struct Running {
+    filter: Filter,
+    outlet: Arc<dyn Outlet>,
+    task: JoinHandle<()>,
+}
+
+impl Running {
+    fn start(target: &HelperTarget, dir: &Path) -> Result<Self> {
+        let (outlet, task): (Arc<dyn Outlet>, _) = match target.kind {
+            TargetKind::Http => {
+                let endpoint = HttpEndpoint::new(target, dir)?;
+                let delivery = Arc::new(Delivery::new(target.label().to_string()));
+                let task = tokio::spawn({
+                    let delivery = delivery.clone();
+                    async move { delivery.run(&endpoint).await }
+                });
+                (delivery, task)
+            }
+            TargetKind::Process => {
+                let delivery = Arc::new(Delivery::new(target.label().to_string()));
+                let endpoint = ProcessEndpoint::start(target, dir.to_path_buf(), delivery.clone())?;
+                let task = tokio::spawn({
+                    let delivery = delivery.clone();
+                    async move { delivery.run(&endpoint).await }
+                });
+                (delivery, task)
+            }
+            TargetKind::Websocket => {
+                let listen = target
+                    .listen
+                    .as_deref()
+                    .context("No listen address for websocket target")?;
+                let listener = std::net::TcpListener::bind(listen)
+                    .with_context(|| format!("无法监听{listen}"))?;
+                listener.set_nonblocking(true)?;
+                let listener = TcpListener::from_std(listener)?;
+                let server = Arc::new(PushServer::default());
+                let task = tokio::spawn(server.clone().serve(listener));
+                (server, task)
+            }
+        };
+        Ok(Self {
+            filter: Filter::new(target),
+            outlet,
+            task,
+        })
+    }
+}
+
+impl Running {
+    /// Keeps delivering to the same place with the filters of `target`
+    fn refilter(mut self, target: &HelperTarget) -> Self {
+        self.filter = Filter::new(target);
+        self
+    }
+
+    /// Stops the target and waits until it has let go of its listener or process
+    async fn stop(mut self) {
+        self.task.abort();
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+const TARGET_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+pub async fn helper_worker(inbox: Arc<Inbox>, settings: Arc<RwLock<Settings>>) {
+    // enabled targets as last seen, and the ones of them that could be started
+    let mut targets: Vec<HelperTarget> = Vec::new();
+    let mut running: Vec<(HelperTarget, Running)> = Vec::new();
+    // targets follow the settings even while no frames come in
+    let mut ticker = interval(TARGET_CHECK_INTERVAL);
+    loop {
+        let received = tokio::select! {
+            received = inbox.recv() => Some(received),
+            _ = ticker.tick() => None,
         };
-        debug!(
-            "Method: {direction_char}, {}, {:?}, {}",
-            parsed.id, parsed.msg_type, parsed.method_name
-        );
-        if direction_char == '\u{2191}' {
+        let settings = settings.read().await;
+        let enabled: Vec<HelperTarget> = settings
+            .helper_targets()
+            .iter()
+            .filter(|target| target.enabled)
+            .cloned()
+            .collect();
+        if enabled != targets {
+            running = start_targets(&enabled, running, settings.dir()).await;
+            targets = enabled;
+        }
+        let Some((session_id, parsed)) = received else {
             continue;
+        };
+        process_message(session_id, parsed, &settings, &running);
+    }
+}
+
+/// Starts `targets`, keeping those already in `running` as they are.
+///
+/// Targets that changed are stopped before the new ones start, so a websocket target can bind
+/// the address its previous version held; one that fails to start keeps its previous version.
+async fn start_targets(
+    targets: &[HelperTarget],
+    mut running: Vec<(HelperTarget, Running)>,
+    dir: &Path,
+) -> Vec<(HelperTarget, Running)> {
+    if targets.is_empty() {
+        warn!("没有启用的助手地址");
+    }
+    let mut started = Vec::with_capacity(targets.len());
+    let mut pending = Vec::new();
+    for target in targets {
+        if let Some(index) = running.iter().position(|(old, _)| old == target) {
+            started.push(running.swap_remove(index));
+        } else if let Some(index) = running
+            .iter()
+            .position(|(old, _)| same_listener(old, target))
+        {
+            // only the filters changed, connected helpers stay connected
+            let (_, kept) = running.swap_remove(index);
+            started.push((target.clone(), kept.refilter(target)));
+            info!("已更新助手: {}", target.label());
+        } else {
+            pending.push(target);
         }
-        if let Err(e) = process_message(parsed, settings) {
-            error!("Failed to process message: {e}");
+    }
+    // the rest were changed, removed or disabled
+    let mut stopped = Vec::with_capacity(running.len());
+    for (old, delivery) in running {
+        delivery.stop().await;
+        stopped.push(old);
+    }
+    for target in pending {
+        match Running::start(target, dir) {
+            Ok(delivery) => {
+                info!("已启用助手: {}", target.label());
+                started.push((target.clone(), delivery));
+            }
+            Err(e) => {
+                error!("无法启用助手{}: {e:#}", target.label());
+                let Some(old) = stopped.iter().find(|old| old.label() == target.label()) else {
+                    continue;
+                };
+                match Running::start(old, dir) {
+                    Ok(delivery) => {
+                        warn!("助手{}沿用修改前的配置", old.label());
+                        started.push((old.clone(), delivery));
+                    }
+                    Err(e) => error!("无法恢复助手{}: {e:#}", old.label()),
+                }
+            }
         }
     }
+    started
 }
 
-fn process_message(mut parsed: LiqiMessage, settings: &Settings) -> Result<()> {
-    static CLIENT: LazyLock<Client> = LazyLock::new(|| {
-        reqwest::ClientBuilder::new()
-            .danger_accept_invalid_certs(true)
-            .build()
-            .expect("Failed to create reqwest client")
-    });
-    if !settings.is_method(&parsed.method_name) {
-        return Ok(());
+/// Whether both are websocket targets on the same address, so one can take over the other's listener
+fn same_listener(old: &HelperTarget, new: &HelperTarget) -> bool {
+    old.kind == TargetKind::Websocket
+        && new.kind == TargetKind::Websocket
+        && old.listen.is_some()
+        && old.listen == new.listen
+}
+
+fn process_message(
+    session_id: u64,
+    parsed: LiqiMessage,
+    settings: &Settings,
+    running: &[(HelperTarget, Running)],
+) {
+    let new_round = starts_round(&parsed);
+    for (config, target) in running {
+        // a frame one target cannot take must not keep it from the others
+        let json_data = match build_payload(parsed.clone(), settings, &target.filter) {
+            Ok(Some(json_data)) => json_data,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("[{}] Failed to build helper payload: {e:#}", config.label());
+                continue;
+            }
+        };
+        let liqi_data = json_data.get("liqi").cloned();
+
+        target.outlet.push(session_id, json_data, new_round);
+        info!("发送至助手……");
+
+        if let Some(liqi_data) = liqi_data {
+            target.outlet.push(session_id, liqi_data, false);
+            info!("发送立直至助手……");
+        }
+    }
+}
+
+/// Whether the frame brings the helper up to date from scratch, making earlier payloads of the game moot
+fn starts_round(parsed: &LiqiMessage) -> bool {
+    match parsed.method_name.as_ref() {
+        ".lq.ActionPrototype" => parsed.data["name"] == "ActionNewRound",
+        ".lq.FastTest.syncGame" => true,
+        _ => false,
+    }
+}
+
+/// Builds the JSON posted to the helper for a server frame, `None` if `filter` leaves the frame out
+pub fn build_payload(
+    mut parsed: LiqiMessage,
+    settings: &Settings,
+    filter: &Filter,
+) -> Result<Option<JsonValue>> {
+    if !filter.is_method(settings, &parsed.method_name) {
+        return Ok(None);
     }
     let json_data: JsonValue = match parsed.method_name.as_ref() {
         ".lq.ActionPrototype" => {
             let name = parsed.data["name"].as_str().context("name field invalid")?;
-            if !settings.is_action(name) {
-                return Ok(());
+            if !filter.is_action(settings, name) {
+                return Ok(None);
             }
             if name == "ActionNewRound" {
                 info!("New Round: {}", parsed);
@@ -94,31 +532,122 @@ fn process_message(mut parsed: LiqiMessage, settings: &Settings) -> Result<()> {
         }
         _ => parsed.data,
     };
+    Ok(Some(json_data))
+}
 
-    // post data to API, no verification
-    let res = CLIENT.post(&settings.api_url).json(&json_data).send();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::MessageType;
 
-    spawn(handle_response(res));
-    info!("发送至助手……");
+    fn action(id: usize, name: &str) -> LiqiMessage {
+        LiqiMessage {
+            id,
+            msg_type: MessageType::Notify,
+            method_name: ".lq.ActionPrototype".into(),
+            data: json!({ "name": name }),
+        }
+    }
 
-    if let Some(liqi_data) = json_data.get("liqi") {
-        let res = CLIENT.post(&settings.api_url).json(liqi_data).send();
-        spawn(handle_response(res));
-        info!("发送立直至助手……");
+    fn discard(id: usize) -> LiqiMessage {
+        action(id, "ActionDiscardTile")
     }
 
-    Ok(())
-}
+    fn inbox(capacity: usize, overflow: OverflowPolicy) -> Inbox {
+        Inbox::new(&HelperQueue { capacity, overflow })
+    }
 
-async fn handle_response(
-    res: impl Future<Output = Result<reqwest::Response, reqwest::Error>> + Send + 'static,
-) {
-    match res.await {
-        Ok(_) => {
-            info!("请求小助手已接收");
+    /// Session and id of every queued frame, oldest first
+    fn queued(inbox: &Inbox) -> Vec<(u64, usize)> {
+        let state = inbox.state.lock().unwrap();
+        state
+            .queue
+            .iter()
+            .map(|(session, message)| (*session, message.id))
+            .collect()
+    }
+
+    fn dropped(inbox: &Inbox) -> (u64, u64) {
+        let state = inbox.state.lock().unwrap();
+        (state.dropped, state.dropped_total)
+    }
+
+    #[test]
+    fn drop_oldest_makes_room() {
+        let inbox = inbox(3, OverflowPolicy::DropOldest);
+        for id in 0..5 {
+            inbox.push(1, discard(id));
         }
-        Err(e) => {
-            error!("请求小助手失败: {e}");
+        assert_eq!(queued(&inbox), [(1, 2), (1, 3), (1, 4)]);
+        assert_eq!(dropped(&inbox), (2, 2));
+    }
+
+    #[test]
+    fn drop_newest_keeps_queue() {
+        let inbox = inbox(3, OverflowPolicy::DropNewest);
+        for id in 0..5 {
+            inbox.push(1, discard(id));
+        }
+        assert_eq!(queued(&inbox), [(1, 0), (1, 1), (1, 2)]);
+        assert_eq!(dropped(&inbox), (2, 2));
+    }
+
+    #[test]
+    fn coalesce_drops_superseded_rounds() {
+        let inbox = inbox(4, OverflowPolicy::Coalesce);
+        inbox.push(1, discard(0));
+        inbox.push(2, discard(1));
+        inbox.push(1, discard(2));
+        inbox.push(2, discard(3));
+        // a new round of game 1 makes its earlier frames moot, game 2 is untouched
+        inbox.push(1, action(4, "ActionNewRound"));
+        assert_eq!(queued(&inbox), [(2, 1), (2, 3), (1, 4)]);
+        assert_eq!(dropped(&inbox), (2, 2));
+    }
+
+    #[test]
+    fn coalesce_falls_back_to_oldest() {
+        let inbox = inbox(2, OverflowPolicy::Coalesce);
+        for id in 0..3 {
+            inbox.push(1, discard(id));
+        }
+        assert_eq!(queued(&inbox), [(1, 1), (1, 2)]);
+        assert_eq!(dropped(&inbox), (1, 1));
+    }
+
+    #[test]
+    fn lagging_until_caught_up() {
+        let inbox = inbox(4, OverflowPolicy::DropOldest);
+        for id in 0..3 {
+            inbox.push(1, discard(id));
+        }
+        // three quarters full
+        assert!(inbox.state.lock().unwrap().lagging);
+        for id in 3..6 {
+            inbox.push(1, discard(id));
+        }
+        assert_eq!(dropped(&inbox), (2, 2));
+
+        while inbox.pop().is_some() {}
+        let state = inbox.state.lock().unwrap();
+        assert!(!state.lagging);
+        // the count since running high is reported and reset, the total is kept
+        assert_eq!((state.dropped, state.dropped_total), (0, 2));
+        drop(state);
+
+        for id in 6..11 {
+            inbox.push(1, discard(id));
+        }
+        assert_eq!(dropped(&inbox), (1, 3));
+    }
+
+    #[test]
+    fn not_lagging_below_threshold() {
+        let inbox = inbox(8, OverflowPolicy::DropOldest);
+        for id in 0..5 {
+            inbox.push(1, discard(id));
         }
+        assert!(!inbox.state.lock().unwrap().lagging);
+        assert_eq!(inbox.pop().map(|(_, message)| message.id), Some(0));
     }
 }
@@ -0,0 +1,95 @@
+use bytes::Bytes;
+pub use hudsucker::futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, sync::Arc};
+use tokio::sync::RwLock;
+
+use crate::{parser::LiqiMessage, session::Injector, settings::Settings};
+
+/// Which way a frame travels through the proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+impl Direction {
+    pub fn from_client(self) -> bool {
+        self == Direction::ClientToServer
+    }
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::ClientToServer => write!(f, "\u{2191}"),
+            Direction::ServerToClient => write!(f, "\u{2193}"),
+        }
+    }
+}
+
+/// A decoded Liqi frame on its way through the interceptor chain
+pub struct Frame<'a> {
+    /// Id of the WebSocket session the frame belongs to
+    pub session_id: u64,
+    pub direction: Direction,
+    pub method_name: &'a str,
+    /// The frame as decoded before any interceptor ran
+    pub message: &'a LiqiMessage,
+    /// The bytes that will be forwarded, including changes made by earlier interceptors
+    pub raw: &'a Bytes,
+    /// Queue for pushing synthetic frames to the client of this session
+    pub injector: &'a Injector,
+}
+
+/// What to do with a frame after an interceptor has seen it
+#[derive(Debug, Clone)]
+pub enum Verdict {
+    /// Forward the frame unchanged
+    Pass,
+    /// Forward these bytes instead
+    Modify(Bytes),
+    /// Do not forward the frame, later interceptors will not see it
+    Drop,
+}
+
+/// Hook for observing and rewriting Liqi frames, registered with `build_and_start_proxy`.
+///
+/// Interceptors run in registration order after the built-in helper and mod interceptors.
+pub trait Interceptor: Send + Sync + 'static {
+    fn intercept<'a>(&'a self, frame: Frame<'a>) -> BoxFuture<'a, Verdict>;
+}
+
+/// Runs a built-in interceptor only while its switch in the shared settings is on
+pub(crate) struct Switched {
+    inner: Arc<dyn Interceptor>,
+    settings: Arc<RwLock<Settings>>,
+    switch: fn(&Settings) -> bool,
+}
+
+impl Switched {
+    pub fn new(
+        inner: Arc<dyn Interceptor>,
+        settings: Arc<RwLock<Settings>>,
+        switch: fn(&Settings) -> bool,
+    ) -> Self {
+        Self {
+            inner,
+            settings,
+            switch,
+        }
+    }
+}
+
+impl Interceptor for Switched {
+    fn intercept<'a>(&'a self, frame: Frame<'a>) -> BoxFuture<'a, Verdict> {
+        Box::pin(async move {
+            if (self.switch)(&*self.settings.read().await) {
+                self.inner.intercept(frame).await
+            } else {
+                Verdict::Pass
+            }
+        })
+    }
+}
@@ -1,34 +1,68 @@
 use anyhow::Context;
 use handler::Handler;
-use helper::helper_worker;
-use hudsucker::{Proxy, certificate_authority::RcgenAuthority, rcgen::KeyPair, rustls};
+use helper::{HelperForwarder, Inbox, helper_worker};
+use hudsucker::Proxy;
+use interceptor::Switched;
+use recorder::Recorder;
+use session::Sessions;
 use std::{future::Future, net::SocketAddr, str::FromStr, sync::Arc};
-use tokio::sync::mpsc::channel;
+use upstream::Upstream;
 
+mod assets;
+mod cert;
+mod control;
+mod delivery;
 mod handler;
 mod helper;
+mod interceptor;
+mod liqi_json;
 mod modder;
 mod parser;
 mod proto;
+mod push;
+mod recorder;
+mod reload;
+mod replay;
+mod self_update;
+mod session;
 mod settings;
+mod setup;
+mod socks;
+mod subprocess;
+mod update;
+mod upstream;
 
 pub use crate::{
+    cert::{CaCert, CertFormat},
+    interceptor::{BoxFuture, Direction, Frame, Interceptor, Verdict},
     modder::Modder,
-    settings::{ModSettings, Settings},
+    parser::{
+        LiqiMessage, MessageType, Parser, decode_action, encode, encode_action, encode_frame,
+    },
+    recorder::CaptureRecord,
+    replay::replay,
+    self_update::check_app_update,
+    session::Injector,
+    settings::{AppUpdate, HelperQueue, ModSettings, OverflowPolicy, Settings, UpdateSource},
 };
 pub use anyhow::Result;
 pub use tokio::sync::RwLock;
 pub use tracing::{info, warn};
+pub use tracing_subscriber::filter::LevelFilter;
 
 pub(crate) const ARBITRARY_MD5: &str = "0123456789abcdef0123456789abcdef";
 
-pub fn init_trace() {
+pub fn init_trace(log_level: LevelFilter) {
     let timer = tracing_subscriber::fmt::time::ChronoLocal::new("%H:%M:%S%.3f".to_string());
     let filter = tracing_subscriber::EnvFilter::builder()
-        .with_default_directive(tracing_subscriber::filter::LevelFilter::WARN.into())
+        .with_default_directive(LevelFilter::WARN.into())
         .from_env()
         .unwrap_or_default()
-        .add_directive("majsoul_max_rs=info".parse().unwrap_or_default());
+        .add_directive(
+            format!("majsoul_max_rs={log_level}")
+                .parse()
+                .unwrap_or_default(),
+        );
     tracing_subscriber::fmt()
         .with_env_filter(filter)
         .with_timer(timer)
@@ -36,51 +70,101 @@ pub fn init_trace() {
         .init();
 }
 
-fn generate_ca() -> Result<RcgenAuthority> {
-    const KEY_PAIR: &str = include_str!("./ca/hudsucker.key");
-    const CA_CERT: &str = include_str!("./ca/hudsucker.cer");
-    let key_pair = KeyPair::from_pem(KEY_PAIR).context("Failed to parse key pair")?;
-    let issuer = hudsucker::rcgen::Issuer::from_ca_cert_pem(CA_CERT, key_pair)
-        .expect("Failed to parse CA certificate");
-
-    let ca = RcgenAuthority::new(issuer, 1_000, rustls::crypto::aws_lc_rs::default_provider());
-    Ok(ca)
-}
-
+/// Starts the proxy; `interceptors` run after the built-in helper and mod interceptors.
+///
+/// The helper and mod can be switched on and off at runtime through `settings`,
+/// edits to the config files and liqi / lqc.lqbin updates are picked up while running.
 pub async fn build_and_start_proxy<F>(
-    settings: &'static Settings,
+    settings: Arc<RwLock<Settings>>,
     modder: Option<Modder>,
+    interceptors: Vec<Arc<dyn Interceptor>>,
     graceful_shutdown: F,
 ) -> Result<()>
 where
     F: Future<Output = ()> + Send + 'static,
 {
-    let ca = generate_ca()?;
+    let (ca_cert, proxy_addr, socks5_addr, recorder) = {
+        let mut settings = settings.write().await;
+        settings.create_control_token()?;
+        let ca_cert = CaCert::load(settings.dir(), settings.bundled_ca())?;
+        let proxy_addr = SocketAddr::from_str(settings.proxy_addr.as_str())
+            .context("Failed to parse proxy address")?;
+        let socks5_addr = settings
+            .socks5_addr
+            .as_deref()
+            .map(SocketAddr::from_str)
+            .transpose()
+            .context("Failed to parse SOCKS5 address")?;
+        if settings.capture_on() {
+            info!(
+                "抓包已开启, 保存至: {}",
+                settings.dir().join("captures").display()
+            );
+        }
+        let recorder = Recorder::start(settings.dir());
+        (ca_cert, proxy_addr, socks5_addr, recorder)
+    };
+    let socks5 = match socks5_addr {
+        Some(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to bind SOCKS5 address {addr}"))?;
+            Some(tokio::spawn(socks::serve(listener, proxy_addr)))
+        }
+        None => None,
+    };
+    let ca = ca_cert.authority()?;
+    let upstream = Upstream::new(settings.clone())?;
 
-    let proxy_addr = SocketAddr::from_str(settings.proxy_addr.as_str())
-        .context("Failed to parse proxy address")?;
+    let mut chain: Vec<Arc<dyn Interceptor>> = Vec::with_capacity(interceptors.len() + 2);
+    let inbox = Arc::new(Inbox::new(settings.read().await.helper_queue()));
+    // start helper worker
+    info!("Helper worker started");
+    let helper = tokio::spawn(helper_worker(inbox.clone(), settings.clone()));
+    chain.push(Arc::new(Switched::new(
+        Arc::new(HelperForwarder::new(inbox)),
+        settings.clone(),
+        Settings::helper_on,
+    )));
     let modder = modder.map(Arc::new);
-
-    let (tx, helper) = if settings.helper_on() {
-        let (tx, rx) = channel(32);
-        // start helper worker
-        info!("Helper worker started");
-        let helper_handle = tokio::spawn(helper_worker(rx, settings));
-        (Some(tx), Some(helper_handle))
-    } else {
-        (None, None)
-    };
-    let handler = Handler::new(tx, modder, settings);
+    if let Some(modder) = &modder {
+        chain.push(Arc::new(Switched::new(
+            modder.clone(),
+            settings.clone(),
+            Settings::mod_on,
+        )));
+    }
+    chain.extend(interceptors);
+    let sessions = Sessions::default();
+    let watcher = tokio::spawn(reload::watch_config(settings.clone(), modder.clone()));
+    let updater = tokio::spawn(update::update_worker(
+        settings.clone(),
+        modder.clone(),
+        sessions.clone(),
+    ));
+    let handler = Handler::new(
+        chain,
+        recorder,
+        settings,
+        modder,
+        ca_cert,
+        sessions,
+        upstream.clone(),
+    );
     let proxy = Proxy::builder()
         .with_addr(proxy_addr)
         .with_ca(ca)
-        .with_rustls_connector(rustls::crypto::aws_lc_rs::default_provider())
+        .with_http_connector(upstream.https_connector())
         .with_http_handler(handler.clone())
         .with_websocket_handler(handler)
-        .with_graceful_shutdown(async {
+        .with_websocket_connector(upstream.websocket_connector())
+        .with_graceful_shutdown(async move {
             graceful_shutdown.await;
-            if let Some(helper) = helper {
-                helper.abort();
+            helper.abort();
+            watcher.abort();
+            updater.abort();
+            if let Some(socks5) = socks5 {
+                socks5.abort();
             }
         })
         .build()
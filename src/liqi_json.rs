@@ -0,0 +1,422 @@
+use anyhow::{Context, Result, bail};
+use prost::Message;
+use prost_reflect::prost_types::{
+    DescriptorProto, EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto,
+    FileDescriptorProto, FileDescriptorSet, FileOptions, MessageOptions, MethodDescriptorProto,
+    OneofDescriptorProto, ServiceDescriptorProto,
+    field_descriptor_proto::{Label, Type},
+};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+const PACKAGE: &str = "lq";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Message,
+    Enum,
+}
+
+/// Builds the encoded `FileDescriptorSet` for liqi.desc from the game's liqi.json,
+/// naming fields the way the AutoLiqi releases do so both can be used interchangeably
+pub fn generate_desc(proto_json: &Value) -> Result<Vec<u8>> {
+    let lq = &proto_json["nested"][PACKAGE];
+    let nested = lq["nested"].as_object().context("liqi.json中没有lq包")?;
+
+    let mut kinds = HashMap::new();
+    collect_kinds(PACKAGE, nested, &mut kinds);
+
+    let mut file = FileDescriptorProto {
+        name: Some("liqi.proto".to_string()),
+        package: Some(PACKAGE.to_string()),
+        syntax: Some("proto3".to_string()),
+        options: lq["options"]["go_package"]
+            .as_str()
+            .map(|go_package| FileOptions {
+                go_package: Some(go_package.to_string()),
+                ..Default::default()
+            }),
+        ..Default::default()
+    };
+    for (name, item) in nested {
+        if item.get("methods").is_some() {
+            file.service.push(build_service(name, item)?);
+        } else if item.get("values").is_some() {
+            file.enum_type.push(build_enum(name, item)?);
+        } else {
+            file.message_type
+                .push(build_message(PACKAGE, name, item, &kinds)?);
+        }
+    }
+
+    Ok(FileDescriptorSet { file: vec![file] }.encode_to_vec())
+}
+
+fn collect_kinds(scope: &str, nested: &Map<String, Value>, kinds: &mut HashMap<String, Kind>) {
+    for (name, item) in nested {
+        let full_name = format!("{scope}.{name}");
+        if item.get("values").is_some() {
+            kinds.insert(full_name, Kind::Enum);
+        } else if item.get("fields").is_some() {
+            if let Some(inner) = item["nested"].as_object() {
+                collect_kinds(&full_name, inner, kinds);
+            }
+            kinds.insert(full_name, Kind::Message);
+        }
+    }
+}
+
+fn build_message(
+    scope: &str,
+    name: &str,
+    item: &Value,
+    kinds: &HashMap<String, Kind>,
+) -> Result<DescriptorProto> {
+    let full_name = format!("{scope}.{name}");
+    let mut message = DescriptorProto {
+        name: Some(name.to_string()),
+        ..Default::default()
+    };
+    if let Some(nested) = item["nested"].as_object() {
+        for (inner_name, inner) in nested {
+            if inner.get("values").is_some() {
+                message.enum_type.push(build_enum(inner_name, inner)?);
+            } else {
+                message
+                    .nested_type
+                    .push(build_message(&full_name, inner_name, inner, kinds)?);
+            }
+        }
+    }
+    let fields = item["fields"]
+        .as_object()
+        .with_context(|| format!("{full_name}没有fields"))?;
+    for (field_name, field) in fields {
+        let number = field["id"]
+            .as_i64()
+            .with_context(|| format!("{full_name}.{field_name}没有id"))?;
+        let type_name = field["type"]
+            .as_str()
+            .with_context(|| format!("{full_name}.{field_name}没有type"))?;
+        let mut descriptor = FieldDescriptorProto {
+            name: Some(snake_case(field_name)),
+            number: Some(number.try_into()?),
+            label: Some(if field["rule"] == "repeated" {
+                Label::Repeated
+            } else {
+                Label::Optional
+            } as i32),
+            proto3_optional: field["options"]["proto3_optional"].as_bool(),
+            ..Default::default()
+        };
+        if let Some(key_type) = field.get("keyType") {
+            // a map is a repeated nested entry message, named the way protoc names it
+            let key_type = key_type
+                .as_str()
+                .and_then(scalar_type)
+                .with_context(|| format!("{full_name}.{field_name}的keyType无效"))?;
+            let entry_name = format!("{}Entry", pascal_case(&snake_case(field_name)));
+            let mut value = entry_field("value", 2);
+            set_type(&mut value, &full_name, field_name, type_name, kinds)?;
+            let mut key = entry_field("key", 1);
+            key.r#type = Some(key_type as i32);
+            message.nested_type.push(DescriptorProto {
+                name: Some(entry_name.clone()),
+                field: vec![key, value],
+                options: Some(MessageOptions {
+                    map_entry: Some(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+            descriptor.label = Some(Label::Repeated as i32);
+            descriptor.r#type = Some(Type::Message as i32);
+            descriptor.type_name = Some(format!(".{full_name}.{entry_name}"));
+        } else {
+            set_type(&mut descriptor, &full_name, field_name, type_name, kinds)?;
+        }
+        message.field.push(descriptor);
+    }
+    if let Some(oneofs) = item["oneofs"].as_object() {
+        for (index, (oneof_name, oneof)) in oneofs.iter().enumerate() {
+            let members = oneof["oneof"]
+                .as_array()
+                .with_context(|| format!("{full_name}.{oneof_name}没有oneof"))?;
+            for member in members {
+                let member = snake_case(member.as_str().unwrap_or_default());
+                let field = message
+                    .field
+                    .iter_mut()
+                    .find(|field| field.name.as_deref() == Some(member.as_str()))
+                    .with_context(|| format!("{full_name}.{oneof_name}的字段{member}不存在"))?;
+                field.oneof_index = Some(index.try_into()?);
+            }
+            message.oneof_decl.push(OneofDescriptorProto {
+                name: Some(snake_case(oneof_name)),
+                ..Default::default()
+            });
+        }
+    }
+    Ok(message)
+}
+
+/// Sets the type of `descriptor` to the scalar, message or enum named `type_name`
+fn set_type(
+    descriptor: &mut FieldDescriptorProto,
+    full_name: &str,
+    field_name: &str,
+    type_name: &str,
+    kinds: &HashMap<String, Kind>,
+) -> Result<()> {
+    match scalar_type(type_name) {
+        Some(scalar) => descriptor.r#type = Some(scalar as i32),
+        None => {
+            let (resolved, kind) = resolve(full_name, type_name, kinds)
+                .with_context(|| format!("{full_name}.{field_name}的类型{type_name}不存在"))?;
+            descriptor.r#type = Some(match kind {
+                Kind::Message => Type::Message,
+                Kind::Enum => Type::Enum,
+            } as i32);
+            descriptor.type_name = Some(format!(".{resolved}"));
+        }
+    }
+    Ok(())
+}
+
+fn entry_field(name: &str, number: i32) -> FieldDescriptorProto {
+    FieldDescriptorProto {
+        name: Some(name.to_string()),
+        number: Some(number),
+        label: Some(Label::Optional as i32),
+        ..Default::default()
+    }
+}
+
+fn build_enum(name: &str, item: &Value) -> Result<EnumDescriptorProto> {
+    let values = item["values"]
+        .as_object()
+        .with_context(|| format!("{name}没有values"))?;
+    let mut descriptor = EnumDescriptorProto {
+        name: Some(name.to_string()),
+        ..Default::default()
+    };
+    for (value_name, number) in values {
+        let number = number
+            .as_i64()
+            .with_context(|| format!("{name}.{value_name}不是整数"))?;
+        descriptor.value.push(EnumValueDescriptorProto {
+            name: Some(value_name.clone()),
+            number: Some(number.try_into()?),
+            ..Default::default()
+        });
+    }
+    Ok(descriptor)
+}
+
+fn build_service(name: &str, item: &Value) -> Result<ServiceDescriptorProto> {
+    let methods = item["methods"]
+        .as_object()
+        .with_context(|| format!("{name}没有methods"))?;
+    let mut service = ServiceDescriptorProto {
+        name: Some(name.to_string()),
+        ..Default::default()
+    };
+    for (method_name, method) in methods {
+        let (Some(input), Some(output)) = (
+            method["requestType"].as_str(),
+            method["responseType"].as_str(),
+        ) else {
+            bail!("{name}.{method_name}缺少requestType或responseType");
+        };
+        service.method.push(MethodDescriptorProto {
+            name: Some(method_name.clone()),
+            input_type: Some(format!(".{PACKAGE}.{input}")),
+            output_type: Some(format!(".{PACKAGE}.{output}")),
+            ..Default::default()
+        });
+    }
+    Ok(service)
+}
+
+/// Looks `name` up from the innermost scope outwards, as protoc does
+fn resolve(scope: &str, name: &str, kinds: &HashMap<String, Kind>) -> Option<(String, Kind)> {
+    let mut scope = scope;
+    loop {
+        let candidate = if scope.is_empty() {
+            name.to_string()
+        } else {
+            format!("{scope}.{name}")
+        };
+        if let Some(kind) = kinds.get(&candidate) {
+            return Some((candidate, *kind));
+        }
+        if scope.is_empty() {
+            return None;
+        }
+        scope = scope.rsplit_once('.').map_or("", |(outer, _)| outer);
+    }
+}
+
+fn scalar_type(name: &str) -> Option<Type> {
+    Some(match name {
+        "double" => Type::Double,
+        "float" => Type::Float,
+        "int32" => Type::Int32,
+        "int64" => Type::Int64,
+        "uint32" => Type::Uint32,
+        "uint64" => Type::Uint64,
+        "sint32" => Type::Sint32,
+        "sint64" => Type::Sint64,
+        "fixed32" => Type::Fixed32,
+        "fixed64" => Type::Fixed64,
+        "sfixed32" => Type::Sfixed32,
+        "sfixed64" => Type::Sfixed64,
+        "bool" => Type::Bool,
+        "string" => Type::String,
+        "bytes" => Type::Bytes,
+        _ => return None,
+    })
+}
+
+/// `hu_tile_bai_da_changed` -> `HuTileBaiDaChanged`, as protoc names map entries
+fn pascal_case(snake: &str) -> String {
+    snake
+        .split('_')
+        .flat_map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase())
+                .into_iter()
+                .chain(chars)
+        })
+        .collect()
+}
+
+/// `saltSha256` -> `salt_sha256`, `hu_tile_baiDa_changed` -> `hu_tile_bai_da_changed`
+fn snake_case(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len() + 4);
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            if !snake.is_empty() && !snake.ends_with('_') {
+                snake.push('_');
+            }
+            snake.push(c.to_ascii_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost_reflect::{DescriptorPool, FieldDescriptor, Kind as FieldKind};
+    use std::collections::BTreeMap;
+
+    fn kind_name(field: &FieldDescriptor) -> String {
+        match field.kind() {
+            FieldKind::Message(message) => message.full_name().to_string(),
+            FieldKind::Enum(enum_type) => enum_type.full_name().to_string(),
+            kind => format!("{kind:?}"),
+        }
+    }
+
+    /// Everything the parser and modder rely on, by full name
+    fn outline(pool: &DescriptorPool) -> BTreeMap<String, Vec<String>> {
+        let mut outline = BTreeMap::new();
+        for message in pool.all_messages() {
+            let fields = message.fields().map(|field| {
+                format!(
+                    "{} = {}: {:?} {} map={} oneof={:?}",
+                    field.name(),
+                    field.number(),
+                    field.cardinality(),
+                    kind_name(&field),
+                    field.is_map(),
+                    field
+                        .containing_oneof()
+                        .map(|oneof| oneof.name().to_string()),
+                )
+            });
+            outline.insert(message.full_name().to_string(), fields.collect());
+        }
+        for enum_type in pool.all_enums() {
+            let values = enum_type
+                .values()
+                .map(|value| format!("{} = {}", value.name(), value.number()));
+            outline.insert(enum_type.full_name().to_string(), values.collect());
+        }
+        for service in pool.services() {
+            let methods = service.methods().map(|method| {
+                format!(
+                    "{}({}) -> {}",
+                    method.name(),
+                    method.input().full_name(),
+                    method.output().full_name()
+                )
+            });
+            outline.insert(service.full_name().to_string(), methods.collect());
+        }
+        outline
+    }
+
+    #[test]
+    fn generated_desc_matches_shipped_desc() {
+        let proto_json: Value =
+            serde_json::from_slice(include_bytes!("../liqi_config/liqi.json")).unwrap();
+        let generated =
+            DescriptorPool::decode(generate_desc(&proto_json).unwrap().as_ref()).unwrap();
+        let shipped =
+            DescriptorPool::decode(include_bytes!("../liqi_config/liqi.desc").as_ref()).unwrap();
+
+        let (generated, shipped) = (outline(&generated), outline(&shipped));
+        assert_eq!(
+            generated.keys().collect::<Vec<_>>(),
+            shipped.keys().collect::<Vec<_>>()
+        );
+        for (name, items) in &shipped {
+            assert_eq!(&generated[name], items, "{name}");
+        }
+    }
+
+    #[test]
+    fn builds_maps_and_oneofs() {
+        let proto_json = serde_json::json!({"nested": {"lq": {"nested": {
+            "Item": {"fields": {"id": {"type": "uint32", "id": 1}}},
+            "Bag": {
+                "fields": {
+                    "itemMap": {"keyType": "uint32", "type": "Item", "id": 1},
+                    "count": {"type": "uint32", "id": 2},
+                    "name": {"type": "string", "id": 3},
+                    "extra": {"type": "uint32", "id": 4, "options": {"proto3_optional": true}},
+                },
+                "oneofs": {
+                    "choice": {"oneof": ["count", "name"]},
+                    "_extra": {"oneof": ["extra"]},
+                },
+            },
+        }}}});
+        let pool = DescriptorPool::decode(generate_desc(&proto_json).unwrap().as_ref()).unwrap();
+        let bag = pool.get_message_by_name("lq.Bag").unwrap();
+        assert!(bag.get_field_by_name("extra").unwrap().supports_presence());
+        let outline = outline(&pool);
+        assert_eq!(
+            outline["lq.Bag"],
+            [
+                "item_map = 1: Repeated lq.Bag.ItemMapEntry map=true oneof=None",
+                "count = 2: Optional uint32 map=false oneof=Some(\"choice\")",
+                "name = 3: Optional string map=false oneof=Some(\"choice\")",
+                "extra = 4: Optional uint32 map=false oneof=Some(\"_extra\")",
+            ]
+        );
+        assert_eq!(
+            outline["lq.Bag.ItemMapEntry"],
+            [
+                "key = 1: Optional uint32 map=false oneof=None",
+                "value = 2: Optional lq.Item map=false oneof=None",
+            ]
+        );
+    }
+}
@@ -1,4 +1,42 @@
+use clap::Parser;
 use majsoul_max_rs::*;
+use std::{path::PathBuf, sync::Arc};
+
+/// 命令行参数, 仅对本次运行生效, 不会写回settings.json
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// 配置目录, 默认为./liqi_config, 不存在时使用程序所在目录下的liqi_config
+    #[arg(long)]
+    config_dir: Option<PathBuf>,
+    /// 代理监听地址, 如 127.0.0.1:23410
+    #[arg(long)]
+    proxy_addr: Option<String>,
+    /// SOCKS5代理监听地址, 如 127.0.0.1:23411, 默认不开启
+    #[arg(long)]
+    socks5_addr: Option<String>,
+    /// 开启或关闭helper
+    #[arg(long, value_name = "BOOL")]
+    helper: Option<bool>,
+    /// 开启或关闭mod
+    #[arg(long = "mod", value_name = "BOOL")]
+    mod_switch: Option<bool>,
+    /// 小助手地址
+    #[arg(long)]
+    api_url: Option<String>,
+    /// 日志等级: off, error, warn, info, debug, trace
+    #[arg(long, default_value_t = LevelFilter::INFO)]
+    log_level: LevelFilter,
+    /// 跳过liqi与mod的自动更新和程序更新检查, 包括运行中的定期检查
+    #[arg(long)]
+    skip_update: bool,
+    /// 导出根证书到指定路径后退出
+    #[arg(long, value_name = "PATH")]
+    export_ca: Option<PathBuf>,
+    /// 导出证书的格式
+    #[arg(long, value_enum, default_value_t = CertFormat::Pem)]
+    ca_format: CertFormat,
+}
 
 async fn shutdown_signal() {
     tokio::signal::ctrl_c()
@@ -8,7 +46,8 @@ async fn shutdown_signal() {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    init_trace();
+    let args = Args::parse();
+    init_trace(args.log_level);
 
     // print red declaimer text
     println!(
@@ -24,9 +63,42 @@ async fn main() -> Result<()> {
         env!("CARGO_PKG_VERSION")
     );
 
-    let settings = Box::new(Settings::new(std::path::Path::new("./liqi_config"))?);
-    let settings: &'static Settings = Box::leak(settings);
-    let mod_settings = RwLock::new(ModSettings::new(settings)?);
+    let mut settings = Settings::new(args.config_dir.as_deref())?;
+    if let Some(proxy_addr) = args.proxy_addr {
+        settings.proxy_addr = proxy_addr;
+    }
+    if let Some(socks5_addr) = args.socks5_addr {
+        settings.socks5_addr = Some(socks5_addr);
+    }
+    if let Some(api_url) = args.api_url {
+        settings.api_url = api_url;
+        settings.warn_api_url_ignored();
+    }
+    if let Some(helper) = args.helper {
+        settings.set_helper_on(helper);
+    }
+    if let Some(mod_switch) = args.mod_switch {
+        settings.set_mod_on(mod_switch);
+    }
+    if let Some(path) = args.export_ca {
+        let ca = CaCert::load(settings.dir(), settings.bundled_ca())?;
+        ca.export(&path, args.ca_format)?;
+        info!("根证书已导出到: {}", path.display());
+        return Ok(());
+    }
+    if args.skip_update {
+        settings.set_update_check_interval(0);
+    }
+    if settings.app_update().check && !args.skip_update {
+        // in the background, a slow GitHub should not hold up the proxy
+        let settings = settings.clone();
+        tokio::spawn(async move {
+            if let Err(e) = check_app_update(&settings).await {
+                warn!("检查程序更新失败: {e:#}");
+            }
+        });
+    }
+    let mod_settings = RwLock::new(ModSettings::new(&settings)?);
 
     // show mod and helper switch status, green for on, red for off
     println!(
@@ -37,40 +109,35 @@ async fn main() -> Result<()> {
         if settings.helper_on() { "on" } else { "off" }
     );
 
-    if settings.auto_update() {
+    if settings.auto_update() && !args.skip_update {
         info!("自动更新liqi已开启");
         let mut new_settings = settings.clone();
         match new_settings.update().await {
-            Err(e) => warn!("更新liqi失败: {e}"),
+            Err(e) => warn!("更新liqi失败, 继续使用当前版本: {e:#}"),
             Ok(true) => {
-                info!("liqi更新成功, 请重启程序");
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                return Ok(());
+                settings.apply_desc(new_settings);
+                info!("liqi更新成功");
             }
-            _ => (),
+            Ok(false) => (),
         }
     }
 
-    let modder = if settings.mod_on() {
-        // start mod worker
-        info!("Mod worker started");
-        if mod_settings.read().await.auto_update() {
-            info!("自动更新mod已开启");
-            let mut new_mod_settings = mod_settings.read().await.clone();
-            match new_mod_settings.get_lqc().await {
-                Err(e) => warn!("更新mod失败: {e}"),
-                Ok(true) => {
-                    info!("mod更新成功, 请重启程序");
-                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                    return Ok(());
-                }
-                Ok(false) => (),
+    if settings.mod_on() && mod_settings.read().await.auto_update() && !args.skip_update {
+        info!("自动更新mod已开启");
+        let mut new_mod_settings = mod_settings.read().await.clone();
+        match new_mod_settings.get_lqc(settings.update_source()).await {
+            Err(e) => warn!("更新mod失败: {e:#}"),
+            Ok(true) => {
+                mod_settings.write().await.apply_lqc(&new_mod_settings);
+                info!("mod更新成功");
             }
+            Ok(false) => (),
         }
-        Some(Modder::new(mod_settings).await?)
-    } else {
-        None
-    };
+    }
+    // always loaded so the mod can be switched on at runtime
+    let modder = Modder::new(mod_settings).await?;
+    info!("Mod worker started");
 
-    build_and_start_proxy(settings, modder, shutdown_signal()).await
+    let settings = Arc::new(RwLock::new(settings));
+    build_and_start_proxy(settings, Some(modder), Vec::new(), shutdown_signal()).await
 }
@@ -1,13 +1,18 @@
 use crate::{
+    interceptor::{BoxFuture, Frame, Interceptor, Verdict},
+    parser::{MessageType, encode_frame},
     proto::{base::BaseMessage, lq, lq_config::ConfigTables, sheets},
-    settings::ModSettings,
+    settings::{ModSettings, default_skin},
 };
 use anyhow::{Context, Result, anyhow, bail};
 use bytes::Bytes;
 use const_format::formatcp;
 use prost::Message;
 use rand::{rng, seq::IndexedRandom};
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 use tokio::sync::RwLock;
 use tracing::{debug, error, info, warn};
 
@@ -34,8 +39,9 @@ pub struct Safe {
     pub items: Vec<lq::Item>,
 }
 
+/// Item definitions decoded from lqc.lqbin
 #[derive(Default)]
-pub struct Modder {
+struct Tables {
     characters: Vec<sheets::ItemDefinitionCharacter>,
     skins: Vec<sheets::ItemDefinitionSkin>,
     titles: Vec<sheets::ItemDefinitionTitle>,
@@ -43,6 +49,11 @@ pub struct Modder {
     loading_images: Vec<sheets::ItemDefinitionLoadingImage>,
     emojis: HashMap<u32, Vec<u32>>,
     endings: Vec<sheets::SpotRewards>,
+}
+
+#[derive(Default)]
+pub struct Modder {
+    tables: RwLock<Arc<Tables>>,
     mod_settings: RwLock<ModSettings>,
     safe: RwLock<Safe>,
     contract: RwLock<String>,
@@ -71,14 +82,11 @@ pub struct ModifyResult {
     pub inject_msg: Option<Bytes>,
 }
 
-impl Modder {
-    pub async fn new(mod_settings: RwLock<ModSettings>) -> Result<Self> {
-        let config_tables = ConfigTables::decode(mod_settings.read().await.resource.as_ref())
-            .context("Failed to decode config tables")?;
-        let mut modder = Modder {
-            mod_settings,
-            ..Default::default()
-        };
+impl Tables {
+    fn decode(resource: &[u8]) -> Result<Self> {
+        let config_tables =
+            ConfigTables::decode(resource).context("Failed to decode config tables")?;
+        let mut tables = Tables::default();
         for data in config_tables.datas {
             // get '_' splitted words in data.table and data.sheet, turn into CamelCase then join by ""
             let class_name = data
@@ -89,26 +97,26 @@ impl Modder {
                 .collect::<String>();
             match class_name.as_str() {
                 "ItemDefinitionCharacter" => {
-                    modder.characters = to_vec(data.data.as_ref());
+                    tables.characters = to_vec(data.data.as_ref());
                 }
                 "ItemDefinitionSkin" => {
-                    modder.skins = to_vec(data.data.as_ref());
+                    tables.skins = to_vec(data.data.as_ref());
                 }
                 "ItemDefinitionTitle" => {
-                    modder.titles = to_vec(data.data.as_ref());
+                    tables.titles = to_vec(data.data.as_ref());
                 }
                 "ItemDefinitionItem" => {
-                    modder.items = to_vec(data.data.as_ref());
+                    tables.items = to_vec(data.data.as_ref());
                 }
                 "ItemDefinitionLoadingImage" => {
-                    modder.loading_images = to_vec(data.data.as_ref());
+                    tables.loading_images = to_vec(data.data.as_ref());
                 }
                 "CharacterEmoji" => {
                     // one character can have multiple emojis
                     for d in data.data {
                         let emoji = sheets::CharacterEmoji::decode(d.as_ref())
                             .context("Failed to decode CharacterEmoji")?;
-                        modder
+                        tables
                             .emojis
                             .entry(emoji.charid)
                             .or_default()
@@ -116,12 +124,42 @@ impl Modder {
                     }
                 }
                 "SpotRewards" => {
-                    modder.endings = to_vec(data.data.as_ref());
+                    tables.endings = to_vec(data.data.as_ref());
                 }
                 _ => {}
             }
         }
-        Ok(modder)
+        Ok(tables)
+    }
+}
+
+impl Modder {
+    pub async fn new(mod_settings: RwLock<ModSettings>) -> Result<Self> {
+        let tables = Tables::decode(mod_settings.read().await.resource.as_ref())?;
+        Ok(Modder {
+            tables: RwLock::new(Arc::new(tables)),
+            mod_settings,
+            ..Default::default()
+        })
+    }
+
+    /// Swaps in the tables of a freshly downloaded lqc.lqbin, frames already being modified
+    /// finish with the old ones
+    pub async fn apply_lqc(&self, updated: &ModSettings) -> Result<()> {
+        let tables = Tables::decode(updated.resource.as_ref())?;
+        *self.tables.write().await = Arc::new(tables);
+        let mut mod_settings = self.mod_settings.write().await;
+        mod_settings.apply_lqc(updated);
+        mod_settings.write();
+        Ok(())
+    }
+
+    async fn tables(&self) -> Arc<Tables> {
+        self.tables.read().await.clone()
+    }
+
+    pub fn mod_settings(&self) -> &RwLock<ModSettings> {
+        &self.mod_settings
     }
 
     pub async fn modify(
@@ -168,14 +206,18 @@ impl Modder {
                 let mut msg = lq::ResAccountInfo::decode(msg_block.data.as_ref())?;
                 if let Some(ref mut acc) = msg.account
                     && acc.account_id == self.safe.read().await.account_id {
-                        acc.avatar_frame = self.mod_settings.read().await.views_presets
-                            [self.mod_settings.read().await.preset_index as usize]
+                        acc.avatar_frame = self
+                            .mod_settings
+                            .read()
+                            .await
+                            .current_views()
                             .iter()
                             .find(|v| v.slot == 5)
                             .map(|v| v.item_id)
                             .unwrap_or_default();
-                        acc.avatar_id = self.mod_settings.read().await.char_skin
-                            [&self.mod_settings.read().await.main_char];
+                        let mod_settings = self.mod_settings.read().await;
+                        acc.avatar_id = mod_settings.skin_of(mod_settings.main_char);
+                        drop(mod_settings);
                         acc.verified = self.mod_settings.read().await.verified;
                         modified_data = Some(msg.encode_to_vec());
                     }
@@ -186,13 +228,22 @@ impl Modder {
                 msg.characters
                     .clone_into(&mut self.safe.write().await.characters);
                 msg.characters.clear();
-                let characters = &self.mod_settings.read().await.char_skin;
-                for char in characters.keys() {
-                    let character = self.perfect_character(*char).await?;
+                // perfect_character writes the settings, nothing may be held across it
+                let characters: Vec<u32> = self
+                    .mod_settings
+                    .read()
+                    .await
+                    .char_skin
+                    .keys()
+                    .copied()
+                    .collect();
+                for char in characters {
+                    let character = self.perfect_character(char).await?;
                     msg.characters.push(character);
                 }
                 msg.skins.clear();
-                msg.skins.extend(self.skins.iter().map(|s| s.id));
+                msg.skins
+                    .extend(self.tables().await.skins.iter().map(|s| s.id));
                 msg.main_character_id = self.mod_settings.read().await.main_char;
                 msg.character_sort.clear();
                 msg.character_sort
@@ -209,9 +260,9 @@ impl Modder {
                 msg.finished_endings.clear();
                 msg.rewarded_endings.clear();
                 msg.finished_endings
-                    .extend(self.endings.iter().map(|e| e.id));
+                    .extend(self.tables().await.endings.iter().map(|e| e.id));
                 msg.rewarded_endings
-                    .extend(self.endings.iter().map(|e| e.id));
+                    .extend(self.tables().await.endings.iter().map(|e| e.id));
                 modified_data = Some(msg.encode_to_vec());
             }
             name if name == ".lq.Lobby.login" || name == ".lq.Lobby.oauth2Login" => {
@@ -230,26 +281,9 @@ impl Modder {
                         .await
                         .loading_image
                         .clone_from(&account.loading_image);
-                    match self
-                        .mod_settings
-                        .read()
-                        .await
-                        .char_skin
-                        .get(&self.mod_settings.read().await.main_char)
-                    {
-                        Some(av) => {
-                            account.avatar_id = *av;
-                        }
-                        _ => {
-                            account.avatar_id = {
-                                let id_str =
-                                    format!("{}", self.mod_settings.read().await.main_char);
-                                let slice = &id_str[4..];
-                                let id_str = format!("40{slice}01");
-                                id_str.parse().context("Failed to parse avatar id")?
-                            }
-                        }
-                    }
+                    let mod_settings = self.mod_settings.read().await;
+                    account.avatar_id = mod_settings.skin_of(mod_settings.main_char);
+                    drop(mod_settings);
                     if !self.mod_settings.read().await.nickname.is_empty() {
                         account
                             .nickname
@@ -296,7 +330,8 @@ impl Modder {
             ".lq.Lobby.fetchTitleList" => {
                 let mut msg = lq::ResTitleList::decode(msg_block.data.as_ref())?;
                 msg.title_list.clear();
-                msg.title_list.extend(self.titles.iter().map(|t| t.id));
+                msg.title_list
+                    .extend(self.tables().await.titles.iter().map(|t| t.id));
                 modified_data = Some(msg.encode_to_vec());
             }
             ".lq.Lobby.fetchRoom" => {
@@ -359,12 +394,14 @@ impl Modder {
                         .characters
                         .clone_into(&mut self.safe.write().await.characters);
                     char_info.characters.clear();
-                    for charid in self.characters.iter().map(|c| c.id) {
+                    for charid in self.tables().await.characters.iter().map(|c| c.id) {
                         let character = self.perfect_character(charid).await?;
                         char_info.characters.push(character);
                     }
                     char_info.skins.clear();
-                    char_info.skins.extend(self.skins.iter().map(|s| s.id));
+                    char_info
+                        .skins
+                        .extend(self.tables().await.skins.iter().map(|s| s.id));
                     char_info.main_character_id = self.mod_settings.read().await.main_char;
                     char_info.character_sort.clear();
                     char_info
@@ -383,10 +420,10 @@ impl Modder {
                     char_info.rewarded_endings.clear();
                     char_info
                         .finished_endings
-                        .extend(self.endings.iter().map(|e| e.id));
+                        .extend(self.tables().await.endings.iter().map(|e| e.id));
                     char_info
                         .rewarded_endings
-                        .extend(self.endings.iter().map(|e| e.id));
+                        .extend(self.tables().await.endings.iter().map(|e| e.id));
                 }
                 if let Some(ref mut bag_info) = msg.bag_info
                     && let Some(ref mut bag) = bag_info.bag {
@@ -414,15 +451,13 @@ impl Modder {
                     }
                 }
                 msg.title_list = Some(lq::ResTitleList {
-                    title_list: self.titles.iter().map(|t| t.id).collect(),
+                    title_list: self.tables().await.titles.iter().map(|t| t.id).collect(),
                     ..Default::default()
                 });
+                let mod_settings = self.mod_settings.read().await;
                 msg.random_character = Some(lq::ResRandomCharacter {
-                    enabled: self.mod_settings.read().await.random_char_switch,
-                    pool: self
-                        .mod_settings
-                        .read()
-                        .await
+                    enabled: mod_settings.random_char_switch,
+                    pool: mod_settings
                         .random_char_pool
                         .iter()
                         .map(|(c, s)| lq::RandomCharacter {
@@ -432,6 +467,7 @@ impl Modder {
                         .collect(),
                     error: None,
                 });
+                drop(mod_settings);
                 modified_data = Some(msg.encode_to_vec());
             }
             ".lq.Lobby.fetchServerSettings" => {
@@ -518,7 +554,7 @@ impl Modder {
         bag.items.extend(self.safe.read().await.items.iter().cloned());
         let mut seen = bag.items.iter().map(|item| item.item_id).collect::<HashSet<_>>();
 
-        for item in self.items.iter() {
+        for item in self.tables().await.items.iter() {
             if matches!(item.category, 1 | 2) {
                 continue;
             }
@@ -531,7 +567,7 @@ impl Modder {
             };
             bag.items.push(new_item);
         }
-        for item in self.loading_images.iter() {
+        for item in self.tables().await.loading_images.iter() {
             if !seen.insert(item.id) {
                 continue;
             }
@@ -548,10 +584,8 @@ impl Modder {
             character.is_upgraded = true;
             character.level = 5;
             if p.account_id == self.safe.read().await.account_id {
-                if self.mod_settings.read().await.random_char_switch
-                    && !self.mod_settings.read().await.random_char_pool.is_empty()
-                {
-                    let mod_settings = self.mod_settings.read().await;
+                let mod_settings = self.mod_settings.read().await;
+                if mod_settings.random_char_switch && !mod_settings.random_char_pool.is_empty() {
                     let (charid, skin) = mod_settings
                         .random_char_pool
                         .choose(&mut rng())
@@ -560,10 +594,11 @@ impl Modder {
                     p.avatar_id = *skin;
                     character.skin = *skin;
                 } else {
-                    character.charid = self.mod_settings.read().await.main_char;
-                    p.avatar_id = self.mod_settings.read().await.char_skin[&character.charid];
+                    character.charid = mod_settings.main_char;
+                    p.avatar_id = mod_settings.skin_of(character.charid);
                     character.skin = p.avatar_id;
                 }
+                drop(mod_settings);
                 *character = self.perfect_character(character.charid).await?;
                 if !self.mod_settings.read().await.nickname.is_empty() {
                     p.nickname
@@ -571,19 +606,19 @@ impl Modder {
                 }
                 p.title = self.mod_settings.read().await.title;
                 p.views.clear();
-                p.views.extend(
-                    self.mod_settings.read().await.views_presets
-                        [self.mod_settings.read().await.preset_index as usize]
-                        .clone(),
-                );
+                p.views
+                    .extend_from_slice(self.mod_settings.read().await.current_views());
                 p.views.iter_mut().for_each(|v| {
                     if v.r#type == 1 {
                         v.item_id = v.item_id_list.choose(&mut rng()).unwrap_or(&0).to_owned()
                     }
                 });
                 // avatar_frame id is view.item_id which view.slot is 5
-                p.avatar_frame = self.mod_settings.read().await.views_presets
-                    [self.mod_settings.read().await.preset_index as usize]
+                p.avatar_frame = self
+                    .mod_settings
+                    .read()
+                    .await
+                    .current_views()
                     .iter()
                     .find(|v| v.slot == 5)
                     .map(|v| v.item_id)
@@ -606,29 +641,22 @@ impl Modder {
             ..Default::default()
         };
         character.rewarded_level.extend(vec![1, 2, 3, 4, 5]);
-        let id_str = format!("{id}");
-        let slice = &id_str[4..];
-        let id_str = format!("40{slice}01");
-        let char_id = id_str.parse().context("Failed to parse character id")?;
         character.skin = *self
             .mod_settings
             .write()
             .await
             .char_skin
             .entry(id)
-            //int('40'+str(c)[4:]+'01')
-            .or_insert(char_id);
+            .or_insert(default_skin(id));
         if self.mod_settings.read().await.emoji_on() {
             character
                 .extra_emoji
-                .extend(self.emojis.get(&id).unwrap_or(&vec![]))
+                .extend(self.tables().await.emojis.get(&id).unwrap_or(&vec![]))
         }
         character.views.clear();
-        character.views.extend(
-            self.mod_settings.read().await.views_presets
-                [self.mod_settings.read().await.preset_index as usize]
-                .clone(),
-        );
+        character
+            .views
+            .extend_from_slice(self.mod_settings.read().await.current_views());
         Ok(character)
     }
 
@@ -643,7 +671,7 @@ impl Modder {
         let mut fake = false;
         let method_name = &msg_block.method_name;
         debug!("Request method: {method_name}");
-        let mut inject_data: Option<Vec<u8>> = None;
+        let mut inject_data: Option<Bytes> = None;
         match method_name.as_str() {
             ".lq.Lobby.changeMainCharacter" => {
                 fake = true;
@@ -669,13 +697,12 @@ impl Modder {
                 let update_data = lq::NotifyAccountUpdate {
                     update: Some(account_update),
                 };
-                let blocks = vec![
-                    Block::String(1, ".lq.NotifyAccountUpdate".into()),
-                    Block::String(2, update_data.encode_to_vec().into()),
-                ];
-                let mut inject_buf = vec![0x01];
-                inject_buf.extend(blocks_to_pb(blocks));
-                inject_data = Some(inject_buf);
+                inject_data = Some(encode_frame(
+                    MessageType::Notify,
+                    0,
+                    ".lq.NotifyAccountUpdate",
+                    update_data.encode_to_vec(),
+                )?);
             }
             ".lq.Lobby.addFinishedEnding" => {
                 // drop
@@ -766,13 +793,13 @@ impl Modder {
             buf.extend(msg_block.encode_to_vec());
             Ok(ModifyResult {
                 msg: Some(buf.into()),
-                inject_msg: inject_data.map(|d| d.into()),
+                inject_msg: inject_data,
             })
         } else {
             // return original message
             Ok(ModifyResult {
                 msg: Some(buf.to_owned()),
-                inject_msg: inject_data.map(|d| d.into()),
+                inject_msg: inject_data,
             })
         }
     }
@@ -798,8 +825,9 @@ impl Modder {
                 let mut msg = lq::NotifyRoomPlayerUpdate::decode(msg_block.data.as_ref())?;
                 for player in msg.player_list.iter_mut().chain(msg.robots.iter_mut()) {
                     if player.account_id == self.safe.read().await.account_id {
-                        player.avatar_id = self.mod_settings.read().await.char_skin
-                            [&self.mod_settings.read().await.main_char];
+                        let mod_settings = self.mod_settings.read().await;
+                        player.avatar_id = mod_settings.skin_of(mod_settings.main_char);
+                        drop(mod_settings);
                         if !self.mod_settings.read().await.nickname.is_empty() {
                             self.mod_settings
                                 .read()
@@ -837,6 +865,7 @@ impl Modder {
                 }
                 modified_data = Some(msg.encode_to_vec());
             }
+            #[allow(clippy::collapsible_match)]
             ".lq.NotifyCustomContestSystemMsg" => {
                 if self.mod_settings.read().await.show_server() {
                     let mut msg =
@@ -873,6 +902,29 @@ impl Modder {
     }
 }
 
+impl Interceptor for Modder {
+    fn intercept<'a>(&'a self, frame: Frame<'a>) -> BoxFuture<'a, Verdict> {
+        Box::pin(async move {
+            let res = self
+                .modify(
+                    frame.raw.clone(),
+                    frame.direction.from_client(),
+                    frame.method_name,
+                )
+                .await;
+            if let Some(inj) = res.inject_msg {
+                frame.injector.inject(inj);
+            }
+            match res.msg {
+                // untouched frames are returned as they came in
+                Some(msg) if msg == *frame.raw => Verdict::Pass,
+                Some(msg) => Verdict::Modify(msg),
+                None => Verdict::Drop,
+            }
+        })
+    }
+}
+
 fn add_zone_id(id: u32, name: &str) -> String {
     const CN: &str = "[C\u{feff}N]";
     let zone_code = id >> 23;
@@ -926,50 +978,3 @@ fn encode_account_id2(id: u32) -> u32 {
     }
     z + s + 1e7 as u32
 }
-
-enum Block {
-    _VarInt(u32, u64),
-    String(u32, Bytes),
-}
-
-fn blocks_to_pb(blocks: Vec<Block>) -> Bytes {
-    let mut pb = Vec::new();
-    for block in blocks {
-        match block {
-            Block::_VarInt(id, data) => {
-                // ((d['id'] << 3)+0).to_bytes(length=1, byteorder='little')
-                let bytes = (id << 3).to_le_bytes();
-                let byte = bytes[0];
-                pb.push(byte);
-                pb.extend(to_var_int(data));
-            }
-            Block::String(id, data) => {
-                let bytes = ((id << 3) + 2).to_le_bytes();
-                let byte = bytes[0];
-                pb.push(byte);
-                pb.extend(to_var_int(data.len() as u64));
-                pb.extend(data);
-            }
-        }
-    }
-    pb.into()
-}
-
-fn to_var_int(mut x: u64) -> Bytes {
-    if x == 0 {
-        return Bytes::from_static(&[0]);
-    }
-    let mut data: u64 = 0;
-    let mut base = 0;
-    let mut length = 0;
-    while x > 0 {
-        length += 1;
-        data += (x & 127) << base;
-        x >>= 7;
-        if x > 0 {
-            data += 1 << (base + 7);
-        }
-        base += 8;
-    }
-    data.to_le_bytes().into_iter().take(length).collect()
-}
@@ -2,7 +2,9 @@ use anyhow::{Context, Result, ensure};
 use base64::prelude::*;
 use bytes::Bytes;
 use prost::Message;
-use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, SerializeOptions};
+use prost_reflect::{
+    DescriptorPool, DynamicMessage, MessageDescriptor, MethodDescriptor, SerializeOptions,
+};
 use serde_json::{Value as JsonValue, value::Serializer};
 use std::{collections::HashMap, fmt::Display, sync::Arc};
 
@@ -12,7 +14,7 @@ const SERIALIZE_OPTIONS: SerializeOptions = SerializeOptions::new()
     .skip_default_fields(false)
     .use_proto_field_name(true);
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageType {
     Notify = 1,
     Request = 2,
@@ -46,14 +48,102 @@ impl LiqiMessage {
             data,
         }
     }
+
+    /// Encodes the message back into a wire frame, see [`encode`]
+    pub fn encode(&self, pool: &DescriptorPool) -> Result<Bytes> {
+        encode(&self.method_name, self.msg_type, self.id, &self.data, pool)
+    }
+}
+
+/// Encodes JSON data into a raw Liqi frame, the inverse of [`Parser::parse`]
+///
+/// # Arguments
+/// * `method_name` - The full method name, e.g. ".lq.Lobby.oauth2Login" or ".lq.ActionPrototype"
+/// * `msg_type` - Whether to build a Notify, Request or Response frame
+/// * `msg_id` - The request id, ignored for Notify frames
+/// * `data` - The message body in the JSON shape produced by the parser
+/// * `pool` - Descriptor pool to resolve message types from
+///
+/// # Errors
+/// Returns an error if:
+/// - The method or message type is unknown
+/// - The JSON does not match the message type
+/// - The message id does not fit in 16 bits
+pub fn encode(
+    method_name: &str,
+    msg_type: MessageType,
+    msg_id: usize,
+    data: &JsonValue,
+    pool: &DescriptorPool,
+) -> Result<Bytes> {
+    let message_type = match msg_type {
+        MessageType::Notify => {
+            let message_name = method_name
+                .split('.')
+                .nth(2)
+                .context("Invalid method name format")?;
+            pool.get_message_by_name(&to_fqn(message_name))
+                .context(format!("Invalid message type: {message_name}"))?
+        }
+        MessageType::Request | MessageType::Response => {
+            let parts: Vec<&str> = method_name.split('.').collect();
+            ensure!(parts.len() >= 4, "Invalid method name format");
+            let method = find_method(pool, parts[1], parts[2], parts[3])?;
+            if msg_type == MessageType::Request {
+                method.input()
+            } else {
+                method.output()
+            }
+        }
+    };
+
+    // Re-encode nested action data if present
+    let mut data = data.clone();
+    if msg_type == MessageType::Notify
+        && let Some(action_obj) = data.get("data").filter(|d| d.is_object())
+    {
+        let action_name = data["name"].as_str().context("name field invalid")?;
+        let b64 = encode_action(action_name, action_obj, pool)?;
+        data["data"] = b64.into();
+    }
+
+    let dyn_msg = DynamicMessage::deserialize(message_type, &data)?;
+    encode_frame(msg_type, msg_id, method_name, dyn_msg.encode_to_vec())
+}
+
+/// Wraps an already encoded message body into a raw Liqi frame
+///
+/// # Errors
+/// Returns an error if the message id of a Request or Response does not fit in 16 bits
+pub fn encode_frame(
+    msg_type: MessageType,
+    msg_id: usize,
+    method_name: &str,
+    data: Vec<u8>,
+) -> Result<Bytes> {
+    let mut buf = vec![msg_type as u8];
+    if msg_type != MessageType::Notify {
+        let msg_id = u16::try_from(msg_id).context("Message id out of range")?;
+        buf.extend(msg_id.to_le_bytes());
+    }
+    let msg_block = BaseMessage {
+        // responses are matched by id and carry no method name
+        method_name: if msg_type == MessageType::Response {
+            String::new()
+        } else {
+            method_name.to_string()
+        },
+        data,
+    };
+    buf.extend(msg_block.encode_to_vec());
+    Ok(buf.into())
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Parser {
     total: usize,
     pub respond_type: HashMap<usize, (Arc<str>, MessageDescriptor)>,
-    proto_json: &'static JsonValue,
-    pub pool: &'static DescriptorPool,
+    pub pool: DescriptorPool,
 }
 
 fn dyn_to_json(msg: &DynamicMessage) -> Result<JsonValue> {
@@ -61,11 +151,10 @@ fn dyn_to_json(msg: &DynamicMessage) -> Result<JsonValue> {
 }
 
 impl Parser {
-    pub fn new(proto_json: &'static JsonValue, pool: &'static DescriptorPool) -> Self {
+    pub fn new(pool: DescriptorPool) -> Self {
         Self {
             total: 0,
             respond_type: HashMap::new(),
-            proto_json,
             pool,
         }
     }
@@ -125,7 +214,7 @@ impl Parser {
         if let Some(b64) = data_obj.get("data") {
             let action_name = data_obj["name"].as_str().context("name field invalid")?;
             let b64 = b64.as_str().unwrap_or_default();
-            let action_obj = decode_action(action_name, b64, self.pool)?;
+            let action_obj = decode_action(action_name, b64, &self.pool)?;
             data_obj
                 .as_object_mut()
                 .context("data is not an object")?
@@ -146,31 +235,16 @@ impl Parser {
         let parts: Vec<&str> = method_name.split('.').collect();
         ensure!(parts.len() >= 4, "Invalid method name format");
 
-        // Lookup method details in proto JSON
-        let proto_domain =
-            &self.proto_json["nested"][parts[1]]["nested"][parts[2]]["methods"][parts[3]];
+        // Lookup method details in the service descriptors
+        let method = find_method(&self.pool, parts[1], parts[2], parts[3])?;
 
         // Decode request
-        let req_type_name = proto_domain["requestType"]
-            .as_str()
-            .context("Invalid request type")?;
-        let req_type = self
-            .pool
-            .get_message_by_name(&to_fqn(req_type_name))
-            .context(format!("Invalid request type: {req_type_name}"))?;
-        let dyn_msg = DynamicMessage::decode(req_type, msg_block.data.as_ref())?;
+        let dyn_msg = DynamicMessage::decode(method.input(), msg_block.data.as_ref())?;
         let data_obj = dyn_to_json(&dyn_msg)?;
 
         // Store response type for later
-        let res_type_name = proto_domain["responseType"]
-            .as_str()
-            .context("Invalid response type")?;
-        let resp_type = self
-            .pool
-            .get_message_by_name(&to_fqn(res_type_name))
-            .context(format!("Invalid response type: {res_type_name}"))?;
         self.respond_type
-            .insert(msg_id, (method_name.clone(), resp_type));
+            .insert(msg_id, (method_name.clone(), method.output()));
 
         Ok((msg_id, method_name, data_obj))
     }
@@ -210,6 +284,25 @@ fn to_fqn(method_name: &str) -> String {
     format!("lq.{method_name}")
 }
 
+/// Resolves an RPC such as `.lq.Lobby.oauth2Login` to its method descriptor
+///
+/// # Arguments
+/// * `package` - The proto package, e.g. "lq"
+/// * `service` - The service name, e.g. "Lobby"
+/// * `method` - The method name, e.g. "oauth2Login"
+fn find_method(
+    pool: &DescriptorPool,
+    package: &str,
+    service: &str,
+    method: &str,
+) -> Result<MethodDescriptor> {
+    pool.get_service_by_name(&format!("{package}.{service}"))
+        .context(format!("Invalid service: {package}.{service}"))?
+        .methods()
+        .find(|m| m.name() == method)
+        .context(format!("Invalid method: {package}.{service}.{method}"))
+}
+
 pub fn decode_action(name: &str, data: &str, pool: &DescriptorPool) -> Result<JsonValue> {
     let mut decoded = BASE64_STANDARD.decode(data)?;
     wtf_decode(&mut decoded);
@@ -220,6 +313,18 @@ pub fn decode_action(name: &str, data: &str, pool: &DescriptorPool) -> Result<Js
     dyn_to_json(&action_msg)
 }
 
+/// Encodes an action as its base64 wire form, the inverse of [`decode_action`]
+pub fn encode_action(name: &str, data: &JsonValue, pool: &DescriptorPool) -> Result<String> {
+    let action_type = pool
+        .get_message_by_name(&to_fqn(name))
+        .context(format!("Invalid action type: {name}"))?;
+    let action_msg = DynamicMessage::deserialize(action_type, data)?;
+    let mut encoded = action_msg.encode_to_vec();
+    // the xor only depends on length and position, so it is its own inverse
+    wtf_decode(&mut encoded);
+    Ok(BASE64_STANDARD.encode(encoded))
+}
+
 fn wtf_decode(data: &mut [u8]) {
     const KEYS: [u8; 9] = [0x84, 0x5E, 0x4E, 0x42, 0x39, 0xA2, 0x1F, 0x60, 0x1C];
     let base = 23 ^ data.len();
@@ -229,3 +334,106 @@ fn wtf_decode(data: &mut [u8]) {
         .enumerate()
         .for_each(|(i, (key, b))| *b ^= (base + 5 * i + *key as usize) as u8);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn pool() -> DescriptorPool {
+        DescriptorPool::decode(include_bytes!(concat!(env!("OUT_DIR"), "/liqi_desc.bin")).as_ref())
+            .expect("embedded liqi.desc")
+    }
+
+    /// Encodes `data`, parses it back and checks it survives a second round trip unchanged
+    fn round_trip(
+        parser: &mut Parser,
+        method_name: &str,
+        msg_type: MessageType,
+        msg_id: usize,
+        data: JsonValue,
+    ) -> LiqiMessage {
+        let frame = encode(method_name, msg_type, msg_id, &data, &parser.pool).unwrap();
+        assert_eq!(frame[0], msg_type as u8);
+        let parsed = parser.parse(frame).unwrap();
+        assert_eq!(parsed.msg_type, msg_type);
+        assert_eq!(&*parsed.method_name, method_name);
+        if msg_type != MessageType::Response {
+            // a response can only be parsed once per request
+            let again = parser.parse(parsed.encode(&parser.pool).unwrap()).unwrap();
+            assert_eq!(again.data, parsed.data);
+        }
+        parsed
+    }
+
+    #[test]
+    fn notify_action_round_trip() {
+        let mut parser = Parser::new(pool());
+        let parsed = round_trip(
+            &mut parser,
+            ".lq.ActionPrototype",
+            MessageType::Notify,
+            0,
+            json!({
+                "step": 3,
+                "name": "ActionDiscardTile",
+                "data": { "seat": 1, "tile": "5m", "moqie": true },
+            }),
+        );
+        assert_eq!(parsed.data["step"], 3);
+        assert_eq!(parsed.data["name"], "ActionDiscardTile");
+        assert_eq!(parsed.data["data"]["seat"], 1);
+        assert_eq!(parsed.data["data"]["tile"], "5m");
+        assert_eq!(parsed.data["data"]["moqie"], true);
+    }
+
+    #[test]
+    fn request_response_round_trip() {
+        let mut parser = Parser::new(pool());
+        let request = round_trip(
+            &mut parser,
+            ".lq.Lobby.oauth2Login",
+            MessageType::Request,
+            7,
+            json!({ "type": 7, "access_token": "token" }),
+        );
+        assert_eq!(request.id, 7);
+        assert_eq!(request.data["type"], 7);
+        assert_eq!(request.data["access_token"], "token");
+
+        let response = round_trip(
+            &mut parser,
+            ".lq.Lobby.oauth2Login",
+            MessageType::Response,
+            7,
+            json!({ "account_id": 5 }),
+        );
+        assert_eq!(response.id, 7);
+        assert_eq!(response.data["account_id"], 5);
+    }
+
+    #[test]
+    fn action_xor_round_trip() {
+        let pool = pool();
+        let data = json!({ "seat": 2, "tile": "7z" });
+        let encoded = encode_action("ActionDiscardTile", &data, &pool).unwrap();
+        let decoded = decode_action("ActionDiscardTile", &encoded, &pool).unwrap();
+        assert_eq!(decoded["seat"], 2);
+        assert_eq!(decoded["tile"], "7z");
+    }
+
+    #[test]
+    fn frame_ids_out_of_range() {
+        assert!(
+            encode_frame(
+                MessageType::Request,
+                1 << 16,
+                ".lq.Lobby.oauth2Login",
+                vec![]
+            )
+            .is_err()
+        );
+        // notifies carry no id
+        assert!(encode_frame(MessageType::Notify, 1 << 16, ".lq.ActionPrototype", vec![]).is_ok());
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::{Context, Result};
+use hudsucker::{
+    futures::{SinkExt, StreamExt},
+    tokio_tungstenite::{
+        accept_async,
+        tungstenite::{Message, Utf8Bytes},
+    },
+};
+use serde_json::Value as JsonValue;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{Receiver, Sender, channel, error::TrySendError},
+};
+use tracing::{debug, info, warn};
+
+use crate::delivery::{MAX_ROUND, Outlet};
+
+/// Payloads a connected helper may fall behind by before it is disconnected
+const CLIENT_BUFFER: usize = MAX_ROUND + 256;
+
+/// Pushes helper payloads to helpers connected over WebSocket, one JSON text message each.
+///
+/// A helper connecting mid-game first gets the current round of the latest game from
+/// its start, so it ends up where a helper following from the beginning would be.
+#[derive(Default)]
+pub struct PushServer {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    clients: Vec<(SocketAddr, Sender<Utf8Bytes>)>,
+    /// Latest game with a round start, and its payloads since then
+    round: Option<(u64, Vec<Utf8Bytes>)>,
+}
+
+impl Outlet for PushServer {
+    fn push(&self, session: u64, payload: JsonValue, new_round: bool) {
+        let text = Utf8Bytes::from(payload.to_string());
+        let mut state = self.state.lock().expect("PushServer state poisoned");
+        if new_round {
+            // earlier games are over, or at least not being watched
+            if state
+                .round
+                .as_ref()
+                .is_none_or(|(game, _)| *game <= session)
+            {
+                state.round = Some((session, vec![text.clone()]));
+            }
+        } else if let Some((game, round)) = &mut state.round
+            && *game == session
+            && round.len() < MAX_ROUND
+        {
+            round.push(text.clone());
+        }
+        state
+            .clients
+            .retain(|(addr, client)| match client.try_send(text.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("推送客户端{addr}处理不及, 已断开");
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            });
+    }
+}
+
+impl PushServer {
+    /// Accepts helpers on `listener` until aborted
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        if let Ok(addr) = listener.local_addr() {
+            info!("助手推送服务已启动: ws://{addr}");
+        }
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept push client: {e}");
+                    continue;
+                }
+            };
+            let receiver = self.subscribe(addr);
+            tokio::spawn(async move {
+                match serve_client(stream, receiver).await {
+                    Ok(()) => info!("推送客户端{addr}已断开"),
+                    Err(e) => debug!("Push client {addr} closed: {e:#}"),
+                }
+            });
+        }
+    }
+
+    /// Registers a client, queueing the current round for it first
+    fn subscribe(&self, addr: SocketAddr) -> Receiver<Utf8Bytes> {
+        let (sender, receiver) = channel(CLIENT_BUFFER);
+        let mut state = self.state.lock().expect("PushServer state poisoned");
+        if let Some((_, round)) = &state.round {
+            for text in round {
+                // the buffer holds a whole round
+                let _ = sender.try_send(text.clone());
+            }
+        }
+        state.clients.push((addr, sender));
+        receiver
+    }
+}
+
+async fn serve_client(stream: TcpStream, mut receiver: Receiver<Utf8Bytes>) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let addr = stream.peer_addr()?;
+    let socket = accept_async(stream)
+        .await
+        .context("WebSocket handshake failed")?;
+    info!("推送客户端{addr}已连接");
+    let (mut sink, mut stream) = socket.split();
+    loop {
+        tokio::select! {
+            text = receiver.recv() => {
+                // dropped for falling behind
+                let Some(text) = text else {
+                    sink.send(Message::Close(None)).await?;
+                    return Ok(());
+                };
+                sink.send(Message::Text(text)).await?;
+            }
+            // pings are answered by tungstenite, anything else from the helper is ignored
+            message = stream.next() => match message {
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(e.into()),
+            },
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::{Context, Result};
+use base64::prelude::*;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::File,
+    io::AsyncWriteExt,
+    spawn,
+    sync::mpsc::{Receiver, Sender, channel, error::TrySendError},
+};
+use tracing::{error, warn};
+
+use crate::{interceptor::Direction, parser::LiqiMessage};
+
+/// Start a new capture file once the current one grows past this size
+const MAX_FILE_SIZE: usize = 32 << 20;
+/// Oldest capture files are deleted beyond this count
+const MAX_FILES: usize = 8;
+/// Frames waiting for the disk, newer ones are dropped beyond this
+const MAX_PENDING: usize = 4096;
+
+/// One line of a capture file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CaptureRecord {
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub session: u64,
+    pub direction: Direction,
+    /// The frame as received, base64 encoded
+    pub raw: String,
+    #[serde(default)]
+    pub method_name: Option<String>,
+    #[serde(default)]
+    pub data: Option<JsonValue>,
+}
+
+impl CaptureRecord {
+    pub fn raw_bytes(&self) -> Result<Bytes> {
+        Ok(BASE64_STANDARD.decode(&self.raw)?.into())
+    }
+}
+
+/// Writes frames seen by the handler to rotating JSONL files under `captures`
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    sender: Sender<CaptureRecord>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Recorder {
+    /// Starts the writer, the captures directory is only created once a frame is recorded
+    pub fn start(dir: &Path) -> Self {
+        let (sender, receiver) = channel(MAX_PENDING);
+        spawn(write_captures(dir.join("captures"), receiver));
+        Self {
+            sender,
+            dropped: Arc::default(),
+        }
+    }
+
+    pub fn record(
+        &self,
+        session: u64,
+        direction: Direction,
+        raw: &Bytes,
+        parsed: Option<&LiqiMessage>,
+    ) {
+        let record = CaptureRecord {
+            timestamp: now_millis(),
+            session,
+            direction,
+            raw: BASE64_STANDARD.encode(raw),
+            method_name: parsed.map(|p| p.method_name.to_string()),
+            data: parsed.map(|p| p.data.clone()),
+        };
+        match self.sender.try_send(record) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                // 1, 2, 4, 8... to keep a slow disk from flooding the log
+                if dropped.is_power_of_two() {
+                    warn!("抓包写入过慢, 已丢弃{dropped}帧");
+                }
+            }
+            Err(TrySendError::Closed(_)) => error!("Capture writer stopped, frame not recorded"),
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+async fn write_captures(dir: PathBuf, mut receiver: Receiver<CaptureRecord>) {
+    let mut file: Option<File> = None;
+    let mut written = 0;
+    while let Some(record) = receiver.recv().await {
+        let mut line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize capture record: {e}");
+                continue;
+            }
+        };
+        line.push('\n');
+        if file.is_none() || written + line.len() > MAX_FILE_SIZE {
+            match rotate(&dir).await {
+                Ok(f) => {
+                    file = Some(f);
+                    written = 0;
+                }
+                Err(e) => {
+                    error!("Failed to open capture file: {e}");
+                    continue;
+                }
+            }
+        }
+        let Some(ref mut f) = file else {
+            continue;
+        };
+        if let Err(e) = f.write_all(line.as_bytes()).await {
+            error!("Failed to write capture file: {e}");
+            file = None;
+            continue;
+        }
+        written += line.len();
+    }
+}
+
+/// Opens a fresh capture file and deletes the oldest ones beyond `MAX_FILES`
+async fn rotate(dir: &Path) -> Result<File> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .context("无法创建captures目录")?;
+    let path = dir.join(format!("capture-{}.jsonl", now_millis()));
+    let file = File::create(&path).await?;
+
+    let mut captures = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with("capture-") && name.ends_with(".jsonl") {
+            captures.push(entry.path());
+        }
+    }
+    captures.sort();
+    let excess = captures.len().saturating_sub(MAX_FILES);
+    for old in &captures[..excess] {
+        if let Err(e) = tokio::fs::remove_file(old).await {
+            error!("Failed to remove old capture {}: {e}", old.display());
+        }
+    }
+    Ok(file)
+}
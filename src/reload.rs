@@ -0,0 +1,62 @@
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::RwLock, time::interval};
+use tracing::{info, warn};
+
+use crate::{modder::Modder, settings::Settings};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Polls settings.json and settings.mod.json and applies edits to the running proxy
+pub async fn watch_config(settings: Arc<RwLock<Settings>>, modder: Option<Arc<Modder>>) {
+    let dir = settings.read().await.dir().to_path_buf();
+    let settings_path = dir.join("settings.json");
+    let mod_path = dir.join("settings.mod.json");
+    let mut settings_mtime = modified(&settings_path).await;
+    let mut mod_mtime = modified(&mod_path).await;
+    // the file as last read, to tell which fields were edited
+    let mut on_disk = match Settings::read_file(&dir) {
+        Ok(on_disk) => on_disk,
+        Err(e) => {
+            warn!("无法监视配置文件: {e:#}");
+            return;
+        }
+    };
+
+    let mut ticker = interval(POLL_INTERVAL);
+    loop {
+        ticker.tick().await;
+
+        let mtime = modified(&settings_path).await;
+        if mtime != settings_mtime {
+            settings_mtime = mtime;
+            match Settings::read_file(&dir) {
+                Ok(new) => {
+                    settings.write().await.apply_reload(&on_disk, &new);
+                    on_disk = new;
+                    info!("已重新载入settings.json");
+                }
+                Err(e) => warn!("重新载入settings.json失败, 保留当前配置: {e:#}"),
+            }
+        }
+
+        let Some(modder) = &modder else {
+            continue;
+        };
+        let mtime = modified(&mod_path).await;
+        if mtime != mod_mtime {
+            mod_mtime = mtime;
+            match modder.mod_settings().write().await.reload() {
+                Ok(()) => info!("已重新载入settings.mod.json"),
+                Err(e) => warn!("重新载入settings.mod.json失败, 保留当前配置: {e:#}"),
+            }
+        }
+    }
+}
+
+async fn modified(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
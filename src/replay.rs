@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use serde_json::Value as JsonValue;
+use std::{collections::HashMap, path::Path};
+
+use crate::{
+    helper::{Filter, build_payload},
+    modder::Modder,
+    parser::{LiqiMessage, Parser},
+    recorder::CaptureRecord,
+    settings::Settings,
+};
+
+/// Replays a capture file through the parser, the mod and the helper payload builder,
+/// printing what the proxy would have done with every frame
+pub async fn replay(path: &Path, settings: &Settings, modder: Option<&Modder>) -> Result<()> {
+    let captures = std::fs::read_to_string(path).context("无法读取抓包文件")?;
+    let mut parsers: HashMap<u64, Parser> = HashMap::new();
+
+    for (line_no, line) in captures.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: CaptureRecord =
+            serde_json::from_str(line).with_context(|| format!("无法解析第{}行", line_no + 1))?;
+        let raw = record.raw_bytes()?;
+        let parser = parsers
+            .entry(record.session)
+            .or_insert_with(|| Parser::new(settings.desc.clone()));
+        // keep the state before this frame to decode the modified version with
+        let mut shadow = parser.clone();
+
+        let parsed = match parser.parse(raw.clone()) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                println!(
+                    "[{}] #{} {} 解析失败: {e}",
+                    record.timestamp, record.session, record.direction
+                );
+                continue;
+            }
+        };
+        println!(
+            "[{}] #{} {} {:?} {}",
+            record.timestamp, record.session, record.direction, parsed.msg_type, parsed.method_name
+        );
+
+        if let Some(modder) = modder {
+            let res = modder
+                .modify(
+                    raw.clone(),
+                    record.direction.from_client(),
+                    &parsed.method_name,
+                )
+                .await;
+            match res.msg {
+                None => println!("  mod: 丢弃"),
+                Some(msg) if msg != raw => print_modified(&mut shadow, &parsed, msg),
+                Some(_) => (),
+            }
+            if let Some(inject) = res.inject_msg {
+                match Parser::new(settings.desc.clone()).parse(inject) {
+                    Ok(injected) => println!("  mod: 注入 {injected}"),
+                    Err(e) => println!("  mod: 注入无法解析: {e}"),
+                }
+            }
+        }
+
+        if !record.direction.from_client() {
+            match build_payload(parsed, settings, &Filter::default()) {
+                Ok(Some(payload)) => {
+                    println!("  helper: {payload}");
+                    if let Some(liqi) = payload.get("liqi") {
+                        println!("  helper: {liqi}");
+                    }
+                }
+                Ok(None) => (),
+                Err(e) => println!("  helper: 处理失败: {e}"),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_modified(shadow: &mut Parser, original: &LiqiMessage, modified: Bytes) {
+    let modified = match shadow.parse(modified) {
+        Ok(modified) => modified,
+        Err(e) => {
+            println!("  mod: 修改后无法解析: {e}");
+            return;
+        }
+    };
+    if modified.method_name != original.method_name {
+        println!(
+            "  mod: {} -> {}",
+            original.method_name, modified.method_name
+        );
+    }
+    let mut changes = Vec::new();
+    json_diff("", &original.data, &modified.data, &mut changes);
+    for change in changes {
+        println!("  mod: {change}");
+    }
+}
+
+/// Collects one line per changed leaf, in the form `path: old -> new`
+fn json_diff(path: &str, old: &JsonValue, new: &JsonValue, changes: &mut Vec<String>) {
+    match (old, new) {
+        (JsonValue::Object(old_map), JsonValue::Object(new_map)) => {
+            for (key, old_value) in old_map {
+                let child = format!("{path}.{key}");
+                match new_map.get(key) {
+                    Some(new_value) => json_diff(&child, old_value, new_value, changes),
+                    None => changes.push(format!("{child}: {old_value} -> (removed)")),
+                }
+            }
+            for (key, new_value) in new_map {
+                if !old_map.contains_key(key) {
+                    changes.push(format!("{path}.{key}: (added) -> {new_value}"));
+                }
+            }
+        }
+        (JsonValue::Array(old_items), JsonValue::Array(new_items))
+            if old_items.len() == new_items.len() =>
+        {
+            for (i, (old_value, new_value)) in old_items.iter().zip(new_items).enumerate() {
+                json_diff(&format!("{path}[{i}]"), old_value, new_value, changes);
+            }
+        }
+        _ if old != new => changes.push(format!("{path}: {old} -> {new}")),
+        _ => (),
+    }
+}
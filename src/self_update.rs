@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+use crate::{assets, settings::Settings};
+
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Name of the release archive built for this platform, matching the names in build.yml
+fn platform_asset() -> String {
+    let os = if cfg!(target_os = "android") {
+        "android"
+    } else if cfg!(target_os = "windows") {
+        "windows"
+    } else if cfg!(target_os = "macos") {
+        "macos"
+    } else if cfg!(target_env = "musl") {
+        "musllinux"
+    } else {
+        "linux"
+    };
+    format!(
+        "{}-{os}-{}.zip",
+        env!("CARGO_PKG_NAME"),
+        std::env::consts::ARCH
+    )
+}
+
+/// `1.2.3` or `v1.2.3` -> `[1, 2, 3]`, ignoring pre-release and build suffixes
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim().trim_start_matches('v');
+    let core = version.split(['-', '+']).next()?;
+    core.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// Checks the latest MajsoulMax-rs release and reports it if it is newer than this build,
+/// downloading the archive for this platform when `appUpdate.download` is set
+pub async fn check_app_update(settings: &Settings) -> Result<()> {
+    let app_update = settings.app_update();
+    let release = settings.get_latest_release(&app_update.release_url).await?;
+    let tag = release["tag_name"]
+        .as_str()
+        .context("No tag found in latest release")?;
+    let latest = parse_version(tag).with_context(|| format!("无法解析版本号: {tag}"))?;
+    let current = parse_version(CURRENT_VERSION).context("无法解析当前版本号")?;
+    if latest <= current {
+        info!("MajsoulMax-rs已是最新版本: {CURRENT_VERSION}");
+        return Ok(());
+    }
+    warn!(
+        "MajsoulMax-rs有新版本: {tag}, 当前版本: {CURRENT_VERSION}, 下载地址: {}",
+        release["html_url"].as_str().unwrap_or_default()
+    );
+    if !app_update.download {
+        return Ok(());
+    }
+    let path = download_release(settings, &release, &latest).await?;
+    info!(
+        "新版本已下载到: {}, 请退出后解压替换当前程序",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Saves the archive for this platform of `release`, whose parsed version is `version`
+async fn download_release(
+    settings: &Settings,
+    release: &Value,
+    version: &[u64],
+) -> Result<PathBuf> {
+    let name = platform_asset();
+    let asset = release["assets"]
+        .as_array()
+        .context("No assets found in latest release")?
+        .iter()
+        .find(|asset| asset["name"] == name.as_str())
+        .with_context(|| format!("新版本中没有适用于当前平台的文件: {name}"))?;
+    let url = asset["browser_download_url"]
+        .as_str()
+        .context("No download url found in asset")?;
+    let bytes = settings
+        .github_get(url)?
+        .timeout(std::time::Duration::from_secs(300))
+        .send()
+        .await
+        .context("Failed to download release")?
+        .error_for_status()
+        .context("Failed to download release")?
+        .bytes()
+        .await?;
+    if let Some(digest) = asset["digest"].as_str() {
+        assets::verify_digest(&name, &bytes, digest)?;
+    }
+
+    // named from the parsed version, the tag comes from the release and may hold path separators
+    let version = version
+        .iter()
+        .map(u64::to_string)
+        .collect::<Vec<_>>()
+        .join(".");
+    // next to the executable, the running one is left alone
+    let exe = std::env::current_exe().context("无法获取当前可执行文件路径")?;
+    let path = exe
+        .parent()
+        .context("无法获取当前可执行文件路径的父目录")?
+        .join(name.replace(".zip", &format!("-v{version}.zip")));
+    assets::write_atomic(&path, &bytes)?;
+    Ok(path)
+}
@@ -0,0 +1,143 @@
+use bytes::Bytes;
+use hudsucker::{WebSocketContext, hyper::Uri};
+use prost_reflect::DescriptorPool;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::sync::{
+    Mutex, RwLock,
+    mpsc::{UnboundedReceiver, UnboundedSender, WeakUnboundedSender, unbounded_channel},
+};
+use tracing::{debug, warn};
+
+use crate::parser::Parser;
+
+/// Identifies one proxied WebSocket pair: the client socket and the server it talks to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SessionKey {
+    pub client: SocketAddr,
+    pub server: Uri,
+}
+
+impl From<&WebSocketContext> for SessionKey {
+    fn from(ctx: &WebSocketContext) -> Self {
+        match ctx {
+            WebSocketContext::ClientToServer { src, dst, .. } => Self {
+                client: *src,
+                server: dst.clone(),
+            },
+            WebSocketContext::ServerToClient { src, dst, .. } => Self {
+                client: *dst,
+                server: src.clone(),
+            },
+        }
+    }
+}
+
+/// Pushes synthetic server frames to the client of one session.
+///
+/// Queued frames are sent before the next real server frame, in the order they were injected.
+/// Frames injected once the server to client direction has ended are dropped.
+#[derive(Debug, Clone)]
+pub struct Injector(WeakUnboundedSender<Bytes>);
+
+impl Injector {
+    pub fn inject(&self, msg: Bytes) {
+        let sent = self
+            .0
+            .upgrade()
+            .is_some_and(|sender| sender.send(msg).is_ok());
+        if !sent {
+            warn!("Session closed, injected message dropped");
+        }
+    }
+}
+
+/// The injection queue of a session, accepting frames for as long as it is held
+#[derive(Debug)]
+pub struct Injected {
+    // the only strong sender, injectors hold weak ones
+    _sender: UnboundedSender<Bytes>,
+    receiver: UnboundedReceiver<Bytes>,
+}
+
+impl Injected {
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        self.receiver.recv().await
+    }
+}
+
+/// State owned by a single WebSocket connection, shared by both of its directions.
+#[derive(Debug)]
+pub struct Session {
+    pub id: u64,
+    pub parser: Mutex<Parser>,
+    injector: WeakUnboundedSender<Bytes>,
+    injected: Mutex<Option<Injected>>,
+}
+
+impl Session {
+    pub fn injector(&self) -> Injector {
+        Injector(self.injector.clone())
+    }
+
+    /// Hands the injection queue to the server to client direction, which is the only one writing to the client
+    pub async fn take_injected(&self) -> Option<Injected> {
+        self.injected.lock().await.take()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sessions {
+    sessions: Arc<RwLock<HashMap<SessionKey, Arc<Session>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Sessions {
+    /// Returns the session for `key`, creating it if this is the first direction to arrive
+    pub async fn open(&self, key: SessionKey, parser: impl FnOnce() -> Parser) -> Arc<Session> {
+        let mut sessions = self.sessions.write().await;
+        sessions
+            .entry(key)
+            .or_insert_with_key(|key| {
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                debug!("Session {id} opened: {} <-> {}", key.client, key.server);
+                let (sender, receiver) = unbounded_channel();
+                Arc::new(Session {
+                    id,
+                    parser: Mutex::new(parser()),
+                    injector: sender.downgrade(),
+                    injected: Mutex::new(Some(Injected {
+                        _sender: sender,
+                        receiver,
+                    })),
+                })
+            })
+            .clone()
+    }
+
+    /// Forgets the session once either direction finishes; the other direction keeps its own handle
+    pub async fn close(&self, key: &SessionKey, session: &Arc<Session>) {
+        let mut sessions = self.sessions.write().await;
+        if sessions
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(current, session))
+        {
+            sessions.remove(key);
+            debug!("Session {} closed", session.id);
+        }
+    }
+
+    /// Switches every open session to a new descriptor pool, responses to requests
+    /// already sent are still decoded with the old one
+    pub async fn set_pool(&self, pool: &DescriptorPool) {
+        for session in self.sessions.read().await.values() {
+            session.parser.lock().await.pool = pool.clone();
+        }
+    }
+}
@@ -1,17 +1,25 @@
-use crate::proto::lq::ViewSlot;
-use anyhow::{Context, Result, bail};
+use crate::{
+    assets::{self, LIQI_FILES, LQC_FILE, Staging},
+    liqi_json::generate_desc,
+    proto::lq::ViewSlot,
+};
+use anyhow::{Context, Result, bail, ensure};
+use base64::prelude::*;
 use bytes::Bytes;
 use prost::Message;
 use prost_reflect::{DescriptorPool, prost_types::FileDescriptorSet};
+use rand::{Rng, rng};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 use std::{
+    borrow::Cow,
     collections::{HashMap, HashSet},
     path::{Path, PathBuf},
     sync::LazyLock,
+    time::Duration,
 };
 use tokio::spawn;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -19,14 +27,40 @@ pub struct Settings {
     pub send_method: Vec<String>,
     pub send_action: Vec<String>,
     pub proxy_addr: String,
+    /// Address of the optional SOCKS5 listener feeding the same pipeline as `proxy_addr`
+    #[serde(default)]
+    pub socks5_addr: Option<String>,
     pub api_url: String,
     helper_switch: bool,
     mod_switch: bool,
+    #[serde(default)]
+    capture_switch: bool,
     auto_update: bool,
     liqi_version: String,
     github_token: String,
     #[serde(default)]
     req_proxy: Option<url::Url>,
+    /// Keywords of hosts to intercept, other HTTPS connections are tunnelled untouched
+    #[serde(default = "default_intercept_hosts")]
+    intercept_hosts: Vec<String>,
+    /// Use the publicly known CA compiled into the binary instead of a generated one
+    #[serde(default)]
+    bundled_ca: bool,
+    /// Minutes between background update checks while running, 0 to only check on start
+    #[serde(default = "default_update_check_interval")]
+    update_check_interval: u64,
+    #[serde(default)]
+    update_source: UpdateSource,
+    #[serde(default)]
+    app_update: AppUpdate,
+    #[serde(default)]
+    helper_queue: HelperQueue,
+    /// Where helper payloads go, `apiUrl` alone if empty
+    #[serde(default)]
+    helper_targets: Vec<HelperTarget>,
+    /// Proxies to reach game and other servers through, the first rule matching a host is used
+    #[serde(default)]
+    upstream_proxy: Vec<UpstreamRule>,
     #[serde(skip)]
     methods_set: HashSet<String>,
     #[serde(skip)]
@@ -34,11 +68,273 @@ pub struct Settings {
     #[serde(skip)]
     pub desc: DescriptorPool,
     #[serde(skip)]
-    pub proto_json: Value,
+    pub proto_json: Option<Value>,
     #[serde(skip)]
     dir: PathBuf,
+    /// Bearer token of the control API for this run, empty until created
+    #[serde(skip)]
+    control_token: String,
+}
+
+fn default_intercept_hosts() -> Vec<String> {
+    [
+        "majsoul",
+        "maj-soul",
+        "catmjstudio",
+        "catmajsoul",
+        "mahjongsoul",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_update_check_interval() -> u64 {
+    60
+}
+
+/// Where liqi and lqc.lqbin updates are fetched from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct UpdateSource {
+    /// Base url of version.json and the game resources, can point to a mirror
+    pub game_url: String,
+    /// Latest release API of AutoLiqi or a mirror of it
+    pub release_url: String,
+    /// Directory or zip holding liqi.json / liqi.desc / lqc.lqbin to import instead of downloading,
+    /// relative to the config dir
+    pub local: Option<PathBuf>,
+    /// Build liqi.desc from the game's own liqi.json instead of using the AutoLiqi release
+    pub generate_liqi: bool,
+}
+
+impl Default for UpdateSource {
+    fn default() -> Self {
+        UpdateSource {
+            game_url: "https://game.maj-soul.com/1".to_string(),
+            release_url: "https://api.github.com/repos/Xerxes-2/AutoLiqi/releases/latest"
+                .to_string(),
+            local: None,
+            generate_liqi: false,
+        }
+    }
+}
+
+impl UpdateSource {
+    fn game_url(&self) -> &str {
+        self.game_url.trim_end_matches('/')
+    }
+
+    /// Reads `name` from the local source, `None` if the source does not have it
+    fn read_local(&self, dir: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+        let Some(local) = &self.local else {
+            return Ok(None);
+        };
+        let local = dir.join(local);
+        if local.is_dir() {
+            let path = local.join(name);
+            if !path.is_file() {
+                return Ok(None);
+            }
+            let bytes =
+                std::fs::read(&path).with_context(|| format!("无法读取{}", path.display()))?;
+            return Ok(Some(bytes));
+        }
+        let file = std::fs::File::open(&local)
+            .with_context(|| format!("无法打开本地更新源{}", local.display()))?;
+        let mut archive = zip::ZipArchive::new(file).context("本地更新源不是目录或zip文件")?;
+        // accept the file at any depth, archives often wrap everything in a folder
+        let Some(index) = (0..archive.len()).find(|&i| {
+            archive
+                .name_for_index(i)
+                .is_some_and(|entry| entry == name || entry.ends_with(&format!("/{name}")))
+        }) else {
+            return Ok(None);
+        };
+        let mut entry = archive.by_index(index)?;
+        let mut bytes = Vec::with_capacity(entry.size() as usize);
+        std::io::Read::read_to_end(&mut entry, &mut bytes)?;
+        Ok(Some(bytes))
+    }
+}
+
+/// Sends connections to hosts containing one of `hosts` through `proxy`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamRule {
+    /// Host keywords like `interceptHosts`, empty to match every host
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// `http://`, `socks5://` or `socks5h://` proxy, `null` to connect directly
+    pub proxy: Option<url::Url>,
+}
+
+impl UpstreamRule {
+    fn matches(&self, host: &str) -> bool {
+        self.hosts.is_empty()
+            || self
+                .hosts
+                .iter()
+                .any(|keyword| host.contains(keyword.as_str()))
+    }
+}
+
+/// Checking for new releases of MajsoulMax-rs itself
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AppUpdate {
+    /// Look for a newer release on start
+    pub check: bool,
+    /// Also download the release archive for this platform next to the executable
+    pub download: bool,
+    /// Latest release API of MajsoulMax-rs or a mirror of it
+    pub release_url: String,
+}
+
+impl Default for AppUpdate {
+    fn default() -> Self {
+        AppUpdate {
+            check: true,
+            download: false,
+            release_url: "https://api.github.com/repos/Xerxes-2/MajsoulMax-rs/releases/latest"
+                .to_string(),
+        }
+    }
+}
+
+/// Buffering of decoded frames between the proxy and the helper worker
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HelperQueue {
+    /// Frames waiting for the helper worker before `overflow` applies
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for HelperQueue {
+    fn default() -> Self {
+        HelperQueue {
+            capacity: 256,
+            overflow: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+/// A helper receiving payloads, with its own filters
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct HelperTarget {
+    /// Shown in the log, the url if empty
+    #[serde(default)]
+    pub name: String,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(rename = "type", default)]
+    pub kind: TargetKind,
+    /// Where an `http` target is posted to
+    #[serde(default)]
+    pub url: Option<url::Url>,
+    /// Address a `websocket` target accepts helpers on
+    #[serde(default)]
+    pub listen: Option<String>,
+    /// Program a `process` target runs, in the config dir
+    #[serde(default)]
+    pub command: Option<String>,
+    /// Arguments of `command`
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Methods sent to this target, `sendMethod` if `null`
+    #[serde(default)]
+    pub send_method: Option<Vec<String>>,
+    /// Actions sent to this target, `sendAction` if `null`
+    #[serde(default)]
+    pub send_action: Option<Vec<String>>,
+    #[serde(default)]
+    pub tls: TargetTls,
 }
 
+impl HelperTarget {
+    pub fn label(&self) -> &str {
+        if !self.name.is_empty() {
+            return &self.name;
+        }
+        match self.kind {
+            TargetKind::Http => self.url.as_ref().map(url::Url::as_str),
+            TargetKind::Websocket => self.listen.as_deref(),
+            TargetKind::Process => self.command.as_deref(),
+        }
+        .unwrap_or_default()
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self.kind {
+            TargetKind::Http => {
+                let url = self.url.as_ref().context("http助手缺少url")?;
+                ensure!(
+                    matches!(url.scheme(), "http" | "https"),
+                    "不支持的助手地址: {url}"
+                );
+            }
+            TargetKind::Websocket => {
+                let listen = self.listen.as_deref().context("websocket助手缺少listen")?;
+                listen
+                    .parse::<std::net::SocketAddr>()
+                    .with_context(|| format!("无效的监听地址: {listen}"))?;
+            }
+            TargetKind::Process => {
+                ensure!(
+                    self.command
+                        .as_deref()
+                        .is_some_and(|command| !command.is_empty()),
+                    "process助手缺少command"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a helper target gets its payloads
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum TargetKind {
+    /// Posted to `url`
+    #[default]
+    Http,
+    /// Pushed to helpers connecting to `listen` over WebSocket
+    Websocket,
+    /// Written to the stdin of `command` as JSON lines
+    Process,
+}
+
+/// TLS options of an HTTPS helper target
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TargetTls {
+    /// Check the certificate of the helper, self-signed ones are accepted otherwise
+    pub verify: bool,
+    /// Extra PEM root certificate to trust, relative to the config dir
+    pub ca_cert: Option<PathBuf>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// What happens to frames once the helper queue is full
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued frame to make room
+    DropOldest,
+    /// Drop the new frame
+    DropNewest,
+    /// Drop frames made moot by a later round start of the same game, then the oldest
+    Coalesce,
+}
+
+/// Version recorded for files imported from a local update source
+const LOCAL_VERSION: &str = "local";
+const EMBEDDED_DESC: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/liqi_desc.bin"));
 const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 static REQUEST_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
     reqwest::Client::builder()
@@ -60,40 +356,198 @@ impl Settings {
         builder.build().context("Failed to build HTTP client")
     }
 
-    pub fn new(arg_dir: &Path) -> Result<Self> {
-        let exe = std::env::current_exe().context("无法获取当前可执行文件路径")?;
-        let dir = if arg_dir.is_dir() {
-            arg_dir.to_path_buf()
+    /// GET request to GitHub through `reqProxy`, authenticated with `githubToken` if set
+    pub(crate) fn github_get(&self, url: &str) -> Result<reqwest::RequestBuilder> {
+        let request = self
+            .create_github_client()?
+            .get(url)
+            .timeout(std::time::Duration::from_secs(10));
+        if self.github_token.is_empty() {
+            return Ok(request);
+        }
+        Ok(request
+            .header("Authorization", format!("Bearer {}", self.github_token))
+            .header("X-GitHub-Api-Version", "2022-11-28"))
+    }
+
+    /// Fetches a release from the GitHub API, `url` being its `releases/latest` endpoint
+    pub(crate) async fn get_latest_release(&self, url: &str) -> Result<Value> {
+        let resp = self
+            .github_get(url)?
+            .send()
+            .await
+            .context("Failed to get latest release")?;
+        if resp
+            .headers()
+            .get("X-RateLimit-Remaining")
+            .context("GitHub API rate limit header not found")?
+            == "0"
+        {
+            bail!("GitHub API rate limit exceeded");
+        }
+        Ok(resp.json().await?)
+    }
+
+    /// Loads settings from `arg_dir`, which must exist, or from the default config dir
+    pub fn new(arg_dir: Option<&Path>) -> Result<Self> {
+        let default_dir = Path::new("./liqi_config");
+        let dir = match arg_dir {
+            Some(dir) if !dir.is_dir() => bail!("配置目录{}不存在", dir.display()),
+            Some(dir) => dir.to_path_buf(),
+            None if default_dir.is_dir() => default_dir.to_path_buf(),
+            None => {
+                // current executable path
+                let exe = std::env::current_exe().context("无法获取当前可执行文件路径")?;
+                exe.parent()
+                    .context("无法获取当前可执行文件路径的父目录")?
+                    .join("liqi_config")
+            }
+        };
+        let mut settings = Self::read_file(&dir)?;
+        info!("已载入配置");
+        if !settings.helper_targets.is_empty() {
+            info!("已配置helperTargets, 将不会发送至apiUrl");
+        }
+
+        if let Err(e) = settings.load_desc() {
+            // only the files the last update replaced have a backup
+            if !assets::rollback(&dir, &LIQI_FILES) {
+                return Err(e);
+            }
+            warn!("liqi无法载入, 已回滚到上一版本: {e:#}");
+            settings.load_desc()?;
+            // the backup does not match the recorded version, check again on the next update
+            settings.write_liqi_version(String::new())?;
+        }
+        Ok(settings)
+    }
+
+    /// (Re)loads liqi.desc and liqi.json from the config dir, leaving the current ones in place on error
+    pub fn load_desc(&mut self) -> Result<()> {
+        // read liqi.json from file, only used to cross-check liqi.desc
+        let json_path = self.dir.join("liqi.json");
+        let proto_json: Option<Value> = if json_path.is_file() {
+            Some(
+                serde_json::from_str(
+                    &std::fs::read_to_string(json_path).context("无法读取liqi.json")?,
+                )
+                .context("无法解析liqi.json")?,
+            )
         } else {
-            // current executable path
-            exe.parent()
-                .context("无法获取当前可执行文件路径的父目录")?
-                .join("liqi_config")
+            None
+        };
+
+        // read desc from file, fall back to the one embedded at build time
+        let desc_path = self.dir.join("liqi.desc");
+        let desc = if desc_path.is_file() {
+            let bytes = std::fs::read(&desc_path).context("无法读取liqi.desc")?;
+            let desc = decode_desc(&bytes).context("无法解析liqi.desc")?;
+            if let Some(proto_json) = &proto_json {
+                check_desc(&desc, proto_json)
+                    .context("liqi.desc与liqi.json版本不一致, 请删除两者后重新更新")?;
+            }
+            info!("已载入liqi.desc");
+            desc
+        } else {
+            let desc = decode_desc(EMBEDDED_DESC).context("无法解析内置liqi.desc")?;
+            if let Some(proto_json) = &proto_json
+                && let Err(e) = check_desc(&desc, proto_json)
+            {
+                warn!("内置liqi.desc与liqi.json版本不一致: {e}");
+            }
+            info!("未找到liqi.desc, 使用内置版本");
+            desc
         };
+        self.desc = desc;
+        self.proto_json = proto_json;
+        Ok(())
+    }
+
+    /// Takes over the descriptors loaded by `load_desc` on a copy of these settings after an update
+    pub fn apply_desc(&mut self, updated: Settings) {
+        self.desc = updated.desc;
+        self.proto_json = updated.proto_json;
+        self.liqi_version = updated.liqi_version;
+    }
+
+    /// Reads settings.json in `dir` without loading the liqi descriptors
+    pub(crate) fn read_file(dir: &Path) -> Result<Self> {
         let settings =
             std::fs::read_to_string(dir.join("settings.json")).context("无法读取settings.json")?;
         let mut settings: Settings =
             serde_json::from_str(&settings).context("无法解析settings.json")?;
-        info!("已载入配置");
         settings.methods_set = settings.send_method.iter().cloned().collect();
         settings.actions_set = settings.send_action.iter().cloned().collect();
-
-        // read desc from file
-        let file_descriptor_set_bytes = include_bytes!(concat!(env!("OUT_DIR"), "/liqi_desc.bin"));
-        let file_descriptor_set =
-            FileDescriptorSet::decode(&file_descriptor_set_bytes[..]).unwrap();
-        settings.desc = DescriptorPool::from_file_descriptor_set(file_descriptor_set)
-            .context("无法解析liqi.desc")?;
-
-        // read liqi.json from file
-        settings.proto_json = serde_json::from_str(
-            &std::fs::read_to_string(dir.join("liqi.json")).context("无法读取liqi.json")?,
-        )
-        .context("无法解析liqi.json")?;
-        settings.dir = dir;
+        settings.dir = dir.to_path_buf();
+        for proxy in settings
+            .upstream_proxy
+            .iter()
+            .filter_map(|rule| rule.proxy.as_ref())
+        {
+            ensure!(
+                matches!(proxy.scheme(), "http" | "socks5" | "socks5h") && proxy.host().is_some(),
+                "不支持的上游代理: {proxy}"
+            );
+        }
+        for target in &settings.helper_targets {
+            target.validate()?;
+        }
         Ok(settings)
     }
 
+    /// Applies the fields edited on disk from `old` to `new`.
+    ///
+    /// Fields left alone in the file keep their current value, so command line and
+    /// control API overrides survive unrelated edits.
+    pub(crate) fn apply_reload(&mut self, old: &Settings, new: &Settings) {
+        if old.send_method != new.send_method {
+            self.set_send_method(new.send_method.clone());
+        }
+        if old.send_action != new.send_action {
+            self.set_send_action(new.send_action.clone());
+        }
+        if old.api_url != new.api_url {
+            self.api_url.clone_from(&new.api_url);
+        }
+        if old.helper_switch != new.helper_switch {
+            self.helper_switch = new.helper_switch;
+        }
+        if old.mod_switch != new.mod_switch {
+            self.mod_switch = new.mod_switch;
+        }
+        if old.intercept_hosts != new.intercept_hosts {
+            self.intercept_hosts.clone_from(&new.intercept_hosts);
+        }
+        if old.update_check_interval != new.update_check_interval {
+            self.update_check_interval = new.update_check_interval;
+        }
+        if old.update_source != new.update_source {
+            self.update_source.clone_from(&new.update_source);
+        }
+        if old.capture_switch != new.capture_switch {
+            self.set_capture_on(new.capture_switch);
+        }
+        if old.upstream_proxy != new.upstream_proxy {
+            self.upstream_proxy.clone_from(&new.upstream_proxy);
+        }
+        if old.helper_targets != new.helper_targets {
+            self.helper_targets.clone_from(&new.helper_targets);
+        }
+        // once when apiUrl starts being ignored, and again whenever it is edited meanwhile
+        let targets_added = old.helper_targets.is_empty() && !new.helper_targets.is_empty();
+        let api_url_edited = old.api_url != new.api_url && !new.helper_targets.is_empty();
+        if targets_added || api_url_edited {
+            self.warn_api_url_ignored();
+        }
+        if old.proxy_addr != new.proxy_addr
+            || old.socks5_addr != new.socks5_addr
+            || old.bundled_ca != new.bundled_ca
+            || old.helper_queue != new.helper_queue
+        {
+            warn!("proxyAddr, socks5Addr, bundledCa与helperQueue的修改需重启后生效");
+        }
+    }
+
     pub fn is_method(&self, method: &str) -> bool {
         self.methods_set.contains(method)
     }
@@ -102,21 +556,153 @@ impl Settings {
         self.actions_set.contains(action)
     }
 
+    /// Whether connections to `host` should be intercepted, an empty list intercepts everything
+    pub fn is_intercept_host(&self, host: &str) -> bool {
+        self.intercept_hosts.is_empty()
+            || self
+                .intercept_hosts
+                .iter()
+                .any(|keyword| host.contains(keyword.as_str()))
+    }
+
+    pub fn set_send_method(&mut self, send_method: Vec<String>) {
+        self.methods_set = send_method.iter().cloned().collect();
+        self.send_method = send_method;
+    }
+
+    pub fn set_send_action(&mut self, send_action: Vec<String>) {
+        self.actions_set = send_action.iter().cloned().collect();
+        self.send_action = send_action;
+    }
+
+    pub fn intercept_hosts(&self) -> &[String] {
+        &self.intercept_hosts
+    }
+
     pub fn helper_on(&self) -> bool {
         self.helper_switch
     }
 
+    pub fn set_helper_on(&mut self, on: bool) {
+        self.helper_switch = on;
+    }
+
     pub fn mod_on(&self) -> bool {
         self.mod_switch
     }
 
+    pub fn set_mod_on(&mut self, on: bool) {
+        self.mod_switch = on;
+    }
+
+    pub fn capture_on(&self) -> bool {
+        self.capture_switch
+    }
+
+    pub fn control_token(&self) -> &str {
+        &self.control_token
+    }
+
+    /// Creates the control API token of this run and writes it to control.token for local tools
+    pub fn create_control_token(&mut self) -> Result<()> {
+        let token = BASE64_URL_SAFE_NO_PAD.encode(rng().random::<[u8; 32]>());
+        let path = self.dir.join("control.token");
+        std::fs::write(&path, &token).context("无法写入control.token")?;
+        info!("控制接口令牌已写入: {}", path.display());
+        self.control_token = token;
+        Ok(())
+    }
+
+    pub fn set_capture_on(&mut self, on: bool) {
+        if on && !self.capture_switch {
+            info!(
+                "抓包已开启, 保存至: {}",
+                self.dir.join("captures").display()
+            );
+        } else if !on && self.capture_switch {
+            info!("抓包已关闭");
+        }
+        self.capture_switch = on;
+    }
+
     pub fn auto_update(&self) -> bool {
         self.auto_update
     }
 
+    pub fn bundled_ca(&self) -> bool {
+        self.bundled_ca
+    }
+
+    pub fn update_check_interval(&self) -> Option<Duration> {
+        (self.update_check_interval > 0)
+            .then(|| Duration::from_secs(self.update_check_interval * 60))
+    }
+
+    pub fn set_update_check_interval(&mut self, minutes: u64) {
+        self.update_check_interval = minutes;
+    }
+
+    pub fn update_source(&self) -> &UpdateSource {
+        &self.update_source
+    }
+
+    pub fn app_update(&self) -> &AppUpdate {
+        &self.app_update
+    }
+
+    pub fn helper_queue(&self) -> &HelperQueue {
+        &self.helper_queue
+    }
+
+    /// Warns that `apiUrl` is not used, to be called when it or `helperTargets` is set
+    pub fn warn_api_url_ignored(&self) {
+        if !self.helper_targets.is_empty() {
+            warn!("已配置helperTargets, apiUrl不会生效");
+        }
+    }
+
+    /// Configured helper targets, or one for `apiUrl` taking `sendMethod` / `sendAction`
+    pub fn helper_targets(&self) -> Cow<'_, [HelperTarget]> {
+        if !self.helper_targets.is_empty() {
+            return Cow::Borrowed(&self.helper_targets);
+        }
+        match url::Url::parse(&self.api_url) {
+            Ok(url) => Cow::Owned(vec![HelperTarget {
+                name: String::new(),
+                enabled: true,
+                kind: TargetKind::Http,
+                url: Some(url),
+                listen: None,
+                command: None,
+                args: Vec::new(),
+                send_method: None,
+                send_action: None,
+                tls: TargetTls::default(),
+            }]),
+            Err(_) => Cow::Borrowed(&[]),
+        }
+    }
+
+    /// Upstream proxy for connections to `host`, `None` to connect directly
+    pub fn upstream_proxy(&self, host: &str) -> Option<&url::Url> {
+        self.upstream_proxy
+            .iter()
+            .find(|rule| rule.matches(host))
+            .and_then(|rule| rule.proxy.as_ref())
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Fetches a newer liqi and loads it into these settings, the files on disk are rolled back if it is unusable
     pub async fn update(&mut self) -> Result<bool> {
-        let version = get_version().await?;
-        let prefix = get_proto_prefix(&version).await?;
+        if self.update_source.local.is_some() {
+            return self.import_liqi();
+        }
+        let game_url = self.update_source.game_url();
+        let version = get_version(game_url).await?;
+        let prefix = get_proto_prefix(game_url, &version).await?;
         if self.liqi_version == prefix {
             info!("无需更新liqi, 当前版本: {version}");
             return Ok(false);
@@ -126,97 +712,218 @@ impl Settings {
             self.liqi_version
         );
 
-        let resp = if self.github_token.is_empty() {
-            let client = self.create_github_client()?;
-            client
-                .get("https://api.github.com/repos/Xerxes-2/AutoLiqi/releases/latest")
-                .timeout(std::time::Duration::from_secs(10))
-                .send()
-                .await
-                .context("Failed to get latest release")?
+        let installed = if self.update_source.generate_liqi {
+            self.generate_liqi(&prefix).await?
         } else {
-            let client = self.create_github_client()?;
-            client
-                .get("https://api.github.com/repos/Xerxes-2/AutoLiqi/releases/latest")
-                .header("Authorization", format!("Bearer {}", self.github_token))
-                .header("X-GitHub-Api-Version", "2022-11-28")
-                .timeout(std::time::Duration::from_secs(10))
-                .send()
-                .await
-                .context("Failed to get latest release")?
+            match self.download_release().await? {
+                Some(installed) => installed,
+                None => return Ok(false),
+            }
         };
-        if resp
-            .headers()
-            .get("X-RateLimit-Remaining")
-            .context("GitHub API rate limit header not found")?
-            == "0"
-        {
-            bail!("GitHub API rate limit exceeded");
+        self.load_installed(&installed)?;
+        self.write_liqi_version(prefix)?;
+        Ok(true)
+    }
+
+    /// Validates liqi.json and liqi.desc together and puts them in place, keeping the old ones as backup.
+    ///
+    /// Returns the names of the files written.
+    fn install_liqi(&self, json: Option<&[u8]>, desc: &[u8]) -> Result<Vec<&'static str>> {
+        let pool = decode_desc(desc).context("liqi.desc无法解析")?;
+        let mut staging = Staging::new(&self.dir, &LIQI_FILES);
+        if let Some(json) = json {
+            let proto_json = serde_json::from_slice(json).context("无法解析liqi.json")?;
+            check_desc(&pool, &proto_json).context("liqi.desc与liqi.json版本不一致")?;
+            staging.stage("liqi.json", json)?;
         }
-        let json: Value = resp.json().await?;
+        staging.stage("liqi.desc", desc)?;
+        staging.commit()
+    }
+
+    /// Loads the liqi just installed, putting the previous `installed` files back if it cannot be used
+    fn load_installed(&mut self, installed: &[&str]) -> Result<()> {
+        if let Err(e) = self.load_desc() {
+            assets::rollback(&self.dir, installed);
+            return Err(e.context("更新后的liqi无法载入, 已回滚"));
+        }
+        Ok(())
+    }
+
+    /// Copies liqi from the local update source, generating liqi.desc if only liqi.json is there
+    fn import_liqi(&mut self) -> Result<bool> {
+        let json = self.update_source.read_local(&self.dir, "liqi.json")?;
+        let desc = match self.update_source.read_local(&self.dir, "liqi.desc")? {
+            Some(desc) => desc,
+            None => {
+                let json = json
+                    .as_ref()
+                    .context("本地更新源中没有liqi.json或liqi.desc")?;
+                generate_desc(&serde_json::from_slice(json).context("无法解析liqi.json")?)?
+            }
+        };
+        let unchanged = std::fs::read(self.dir.join("liqi.desc")).is_ok_and(|old| old == desc)
+            && json.as_ref().is_none_or(|json| {
+                std::fs::read(self.dir.join("liqi.json")).is_ok_and(|old| &old == json)
+            });
+        if unchanged {
+            info!("本地更新源中的liqi与当前一致");
+            return Ok(false);
+        }
+        let installed = self.install_liqi(json.as_deref(), &desc)?;
+        self.load_installed(&installed)?;
+        info!("已从本地更新源导入liqi");
+        self.write_liqi_version(LOCAL_VERSION.to_string())?;
+        Ok(true)
+    }
+
+    /// Downloads the game's liqi.json and builds liqi.desc from it, returning the files written
+    async fn generate_liqi(&self, prefix: &str) -> Result<Vec<&'static str>> {
+        let resp = REQUEST_CLIENT
+            .get(format!(
+                "{}/{prefix}/res/proto/liqi.json",
+                self.update_source.game_url()
+            ))
+            .timeout(std::time::Duration::from_secs(30))
+            .send()
+            .await
+            .context("Failed to get liqi.json")?
+            .error_for_status()
+            .context("Failed to get liqi.json")?;
+        let json = resp.bytes().await?;
+        let desc = generate_desc(&serde_json::from_slice(&json).context("无法解析liqi.json")?)?;
+        let installed = self.install_liqi(Some(&json), &desc)?;
+        info!("已根据游戏liqi.json生成liqi.desc");
+        Ok(installed)
+    }
+
+    /// Downloads liqi.json and liqi.desc from the latest AutoLiqi release, returning the files written
+    /// or `None` if it is not out yet
+    async fn download_release(&self) -> Result<Option<Vec<&'static str>>> {
+        let json = self
+            .get_latest_release(&self.update_source.release_url)
+            .await?;
         if json["tag_name"] == self.liqi_version {
             info!("liqi需要更新, 但是AutoLiqi尚未更新, 稍晚再试");
-            return Ok(false);
+            return Ok(None);
         }
         let assets = json["assets"]
             .as_array()
             .context("No assets found in latest release")?;
+        let (mut proto_json, mut desc) = (None, None);
         for asset_item in assets {
-            self.download_asset(asset_item).await?;
+            match self.download_asset(asset_item).await? {
+                Some(("liqi.json", bytes)) => proto_json = Some(bytes),
+                Some((_, bytes)) => desc = Some(bytes),
+                None => {}
+            }
         }
-        // write settings.json
-        self.liqi_version = prefix;
+        let desc = desc.context("No liqi.desc found in latest release")?;
+        self.install_liqi(proto_json.as_deref(), &desc).map(Some)
+    }
+
+    fn write_liqi_version(&mut self, version: String) -> Result<()> {
+        // write settings.json, only touching liqiVersion so command line overrides are not persisted
+        self.liqi_version = version;
         let dir = self.dir.join("settings.json");
-        std::fs::write(dir, serde_json::to_string_pretty(self)?)?;
-        Ok(true)
+        let mut on_disk: Value =
+            serde_json::from_str(&std::fs::read_to_string(&dir).context("无法读取settings.json")?)
+                .context("无法解析settings.json")?;
+        on_disk["liqiVersion"] = self.liqi_version.as_str().into();
+        std::fs::write(dir, serde_json::to_string_pretty(&on_disk)?)?;
+        Ok(())
     }
 
-    pub async fn download_asset(&self, asset_item: &Value) -> Result<()> {
-        const ASSET_NAMES: [&str; 2] = ["liqi.desc", "liqi.json"];
+    /// Downloads a liqi asset of a release, checking it against the digest GitHub lists for it.
+    ///
+    /// Returns `None` for assets other than liqi.json and liqi.desc.
+    pub async fn download_asset(
+        &self,
+        asset_item: &Value,
+    ) -> Result<Option<(&'static str, Bytes)>> {
         let name = asset_item["name"]
             .as_str()
             .context("No name found in asset")?;
-        if !ASSET_NAMES.contains(&name) {
-            return Ok(());
-        }
+        let Some(name) = LIQI_FILES.into_iter().find(|asset| *asset == name) else {
+            return Ok(None);
+        };
         let url = asset_item["browser_download_url"]
             .as_str()
             .context("No download url found in asset")?;
-        let resp = if self.github_token.is_empty() {
-            let client = self.create_github_client()?;
-            client
-                .get(url)
-                .timeout(std::time::Duration::from_secs(10))
-                .send()
-                .await
-                .context("Failed to download asset")?
-        } else {
-            let client = self.create_github_client()?;
-            client
-                .get(url)
-                .header("Authorization", format!("Bearer {}", self.github_token))
-                .header("X-GitHub-Api-Version", "2022-11-28")
-                .timeout(std::time::Duration::from_secs(10))
-                .send()
-                .await
-                .context("Failed to download asset")?
-        };
-
-        let resp = resp
+        let resp = self
+            .github_get(url)?
+            .send()
+            .await
+            .context("Failed to download asset")?
             .error_for_status()
             .context("Failed to download asset")?;
 
         let bytes = resp.bytes().await?;
-        let file_dir = self.dir.join(name);
-        std::fs::write(file_dir, bytes)?;
+        if let Some(digest) = asset_item["digest"].as_str() {
+            assets::verify_digest(name, &bytes, digest)?;
+        }
         info!("下载完成: {name}");
+        Ok(Some((name, bytes)))
+    }
+}
+
+fn decode_desc(bytes: &[u8]) -> Result<DescriptorPool> {
+    let file_descriptor_set = FileDescriptorSet::decode(bytes)?;
+    Ok(DescriptorPool::from_file_descriptor_set(
+        file_descriptor_set,
+    )?)
+}
+
+/// Checks that every message field and rpc described by liqi.json exists in the descriptor pool,
+/// so a desc left over from another protocol version is refused instead of silently misdecoding
+fn check_desc(pool: &DescriptorPool, proto_json: &Value) -> Result<()> {
+    fn check_nested(pool: &DescriptorPool, prefix: &str, nested: &Value) -> Result<()> {
+        let Some(nested) = nested.as_object() else {
+            return Ok(());
+        };
+        for (name, item) in nested {
+            let full_name = format!("{prefix}.{name}");
+            if let Some(fields) = item["fields"].as_object() {
+                let message = pool
+                    .get_message_by_name(&full_name)
+                    .with_context(|| format!("缺少消息: {full_name}"))?;
+                // liqi.json mixes snake_case and camelCase, compare names loosely
+                let normalize = |name: &str| name.replace('_', "").to_lowercase();
+                let known: HashSet<String> =
+                    message.fields().map(|f| normalize(f.name())).collect();
+                for field in fields.keys() {
+                    ensure!(
+                        known.contains(&normalize(field)),
+                        "缺少字段: {full_name}.{field}"
+                    );
+                }
+            }
+            if let Some(methods) = item["methods"].as_object() {
+                let service = pool
+                    .get_service_by_name(&full_name)
+                    .with_context(|| format!("缺少服务: {full_name}"))?;
+                for (method, def) in methods {
+                    let descriptor = service
+                        .methods()
+                        .find(|m| m.name() == method)
+                        .with_context(|| format!("缺少方法: {full_name}.{method}"))?;
+                    ensure!(
+                        def["requestType"] == descriptor.input().name()
+                            && def["responseType"] == descriptor.output().name(),
+                        "方法类型不一致: {full_name}.{method}"
+                    );
+                }
+            }
+            check_nested(pool, &full_name, &item["nested"])?;
+        }
         Ok(())
     }
+
+    check_nested(pool, "lq", &proto_json["nested"]["lq"]["nested"])
 }
 
-async fn get_version() -> Result<String> {
+async fn get_version(game_url: &str) -> Result<String> {
     let resp = REQUEST_CLIENT
-        .get("https://game.maj-soul.com/1/version.json")
+        .get(format!("{game_url}/version.json"))
         .timeout(std::time::Duration::from_secs(10))
         .send()
         .await
@@ -226,11 +933,9 @@ async fn get_version() -> Result<String> {
     Ok(version.to_string())
 }
 
-async fn get_proto_prefix(version: &str) -> Result<String> {
+async fn get_proto_prefix(game_url: &str, version: &str) -> Result<String> {
     let resp = REQUEST_CLIENT
-        .get(format!(
-            "https://game.maj-soul.com/1/resversion{version}.json",
-        ))
+        .get(format!("{game_url}/resversion{version}.json"))
         .timeout(std::time::Duration::from_secs(10))
         .send()
         .await
@@ -242,11 +947,9 @@ async fn get_proto_prefix(version: &str) -> Result<String> {
     Ok(prefix.to_string())
 }
 
-pub async fn get_lqbin_prefix(version: &str) -> Result<String> {
+pub async fn get_lqbin_prefix(game_url: &str, version: &str) -> Result<String> {
     let resp = REQUEST_CLIENT
-        .get(format!(
-            "https://game.maj-soul.com/1/resversion{version}.json"
-        ))
+        .get(format!("{game_url}/resversion{version}.json"))
         .timeout(std::time::Duration::from_secs(10))
         .send()
         .await
@@ -283,6 +986,13 @@ pub struct ModSettings {
     pub resource: Bytes,
     #[serde(skip)]
     dir: PathBuf,
+    #[serde(skip)]
+    dry_run: bool,
+}
+
+/// Default skin of a character, `int('40' + str(c)[4:] + '01')`
+pub fn default_skin(char_id: u32) -> u32 {
+    400_001 + char_id % 100 * 100
 }
 
 impl Default for ModSettings {
@@ -308,6 +1018,7 @@ impl Default for ModSettings {
             version: String::new(),
             resource: Bytes::new(),
             dir: PathBuf::new(),
+            dry_run: false,
         }
     }
 }
@@ -317,8 +1028,17 @@ impl ModSettings {
         // read settings.mod.json, if not exist, create a new one
         let dir = general_settings.dir.join("settings.mod.json");
         // read res from lqc.lqbin
-        let res =
-            std::fs::read(general_settings.dir.join("lqc.lqbin")).context("无法读取lqc.lqbin")?;
+        let mut res =
+            std::fs::read(general_settings.dir.join(LQC_FILE)).context("无法读取lqc.lqbin")?;
+        let mut rolled_back = false;
+        if let Err(e) = assets::validate(LQC_FILE, &res)
+            && assets::rollback(&general_settings.dir, &[LQC_FILE])
+        {
+            warn!("lqc.lqbin无法载入, 已回滚到上一版本: {e:#}");
+            res =
+                std::fs::read(general_settings.dir.join(LQC_FILE)).context("无法读取lqc.lqbin")?;
+            rolled_back = true;
+        }
         let settings = std::fs::read_to_string(dir);
         let settings = match settings {
             Ok(settings) => settings,
@@ -334,12 +1054,31 @@ impl ModSettings {
         };
         let mut settings: ModSettings =
             serde_json::from_str(&settings).context("无法解析settings.mod.json")?;
+        settings.validate().context("settings.mod.json无效")?;
         info!("已载入Mod配置");
         settings.resource = Bytes::from(res);
         settings.dir = general_settings.dir.clone();
+        if rolled_back {
+            // the backup does not match the recorded version, check again on the next update
+            settings.version.clear();
+            settings.write();
+        }
         Ok(settings)
     }
 
+    /// Views of the preset in use
+    pub fn current_views(&self) -> &[ViewSlot] {
+        &self.views_presets[self.preset_index as usize]
+    }
+
+    /// Skin picked for `char_id`, its default skin if there is none
+    pub fn skin_of(&self, char_id: u32) -> u32 {
+        self.char_skin
+            .get(&char_id)
+            .copied()
+            .unwrap_or_else(|| default_skin(char_id))
+    }
+
     pub fn hint_on(&self) -> bool {
         self.hint_switch
     }
@@ -360,10 +1099,14 @@ impl ModSettings {
         self.anti_nickname_censorship
     }
 
-    pub async fn get_lqc(&mut self) -> Result<bool> {
-        // get lqc.lqbin prefix from https://game.maj-soul.com/1/{prefix}/res/config/lqc.lqbin
-        let version = get_version().await?;
-        let prefix = get_lqbin_prefix(&version).await?;
+    pub async fn get_lqc(&mut self, source: &UpdateSource) -> Result<bool> {
+        if source.local.is_some() {
+            return self.import_lqc(source);
+        }
+        // get lqc.lqbin prefix from {game_url}/{prefix}/res/config/lqc.lqbin
+        let game_url = source.game_url();
+        let version = get_version(game_url).await?;
+        let prefix = get_lqbin_prefix(game_url, &version).await?;
 
         if self.version == prefix {
             info!("无需更新lqc.lqbin, 当前版本: {version}");
@@ -375,26 +1118,100 @@ impl ModSettings {
         );
 
         let resp = REQUEST_CLIENT
-            .get(format!(
-                "https://game.maj-soul.com/1/{prefix}/res/config/lqc.lqbin",
-            ))
+            .get(format!("{game_url}/{prefix}/res/config/lqc.lqbin"))
             .timeout(std::time::Duration::from_secs(10))
             .send()
             .await
+            .context("Failed to get lqc.lqbin")?
+            .error_for_status()
             .context("Failed to get lqc.lqbin")?;
 
         let bytes = resp.bytes().await?;
-        let file_dir = self.dir.join("lqc.lqbin");
-        std::fs::write(file_dir, bytes)?;
+        let mut staging = Staging::new(&self.dir, &[LQC_FILE]);
+        staging.stage(LQC_FILE, &bytes)?;
+        staging.commit()?;
         info!("lqc.lqbin更新完成");
         self.version = prefix;
+        self.resource = bytes;
         // write settings.mod.json
         let dir = self.dir.join("settings.mod.json");
         std::fs::write(dir, serde_json::to_string_pretty(self)?)?;
         Ok(true)
     }
 
+    fn import_lqc(&mut self, source: &UpdateSource) -> Result<bool> {
+        let bytes = source
+            .read_local(&self.dir, "lqc.lqbin")?
+            .context("本地更新源中没有lqc.lqbin")?;
+        if bytes == self.resource {
+            info!("本地更新源中的lqc.lqbin与当前一致");
+            return Ok(false);
+        }
+        let mut staging = Staging::new(&self.dir, &[LQC_FILE]);
+        staging.stage(LQC_FILE, &bytes)?;
+        staging.commit()?;
+        info!("已从本地更新源导入lqc.lqbin");
+        self.version = LOCAL_VERSION.to_string();
+        self.resource = Bytes::from(bytes);
+        let dir = self.dir.join("settings.mod.json");
+        std::fs::write(dir, serde_json::to_string_pretty(self)?)?;
+        Ok(true)
+    }
+
+    /// Overwrites the fields present in `patch`, keyed by their names in settings.mod.json.
+    ///
+    /// Nothing is changed if a key is unknown or the result would be invalid.
+    pub fn apply_patch(&mut self, patch: Map<String, Value>) -> Result<()> {
+        let mut value = serde_json::to_value(&*self)?;
+        for (key, field) in patch {
+            if key == "version" || value.get(&key).is_none() {
+                bail!("未知或不可修改的字段: {key}");
+            }
+            value[&key] = field;
+        }
+        self.replace_with(serde_json::from_value(value)?)
+    }
+
+    /// Re-reads settings.mod.json, keeping the current settings if it is invalid
+    pub fn reload(&mut self) -> Result<()> {
+        let settings = std::fs::read_to_string(self.dir.join("settings.mod.json"))
+            .context("无法读取settings.mod.json")?;
+        self.replace_with(serde_json::from_str(&settings).context("无法解析settings.mod.json")?)
+    }
+
+    fn replace_with(&mut self, mut new: ModSettings) -> Result<()> {
+        new.validate()?;
+        new.resource = std::mem::take(&mut self.resource);
+        new.dir = std::mem::take(&mut self.dir);
+        new.dry_run = self.dry_run;
+        *self = new;
+        Ok(())
+    }
+
+    /// Checks what the mod relies on, the same at startup, on reload and when patched
+    fn validate(&self) -> Result<()> {
+        // the mod indexes these directly
+        if self.preset_index as usize >= self.views_presets.len() {
+            bail!("presetIndex超出范围: {}", self.preset_index);
+        }
+        Ok(())
+    }
+
+    /// Takes over the lqc.lqbin fetched by `get_lqc` on a copy of these settings
+    pub fn apply_lqc(&mut self, updated: &ModSettings) {
+        self.version.clone_from(&updated.version);
+        self.resource = updated.resource.clone();
+    }
+
+    /// Keeps changes made through the game in memory only, used when replaying captures
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
     pub fn write(&self) {
+        if self.dry_run {
+            return;
+        }
         let dir = self.dir.join("settings.mod.json");
         let Ok(contend) = serde_json::to_string_pretty(self) else {
             error!("Failed to serialize settings.mod.json");
@@ -407,3 +1224,50 @@ impl ModSettings {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patch(value: Value) -> Map<String, Value> {
+        value.as_object().cloned().unwrap()
+    }
+
+    #[test]
+    fn patches_default_mod_settings() {
+        let mut settings = ModSettings::default();
+        settings
+            .apply_patch(patch(json!({ "nickname": "x" })))
+            .unwrap();
+        assert_eq!(settings.nickname, "x");
+        // no skin picked yet, the default one is used
+        assert_eq!(settings.skin_of(settings.main_char), 400101);
+    }
+
+    #[test]
+    fn rejects_invalid_mod_patches() {
+        let mut settings = ModSettings::default();
+        for invalid in [
+            json!({ "version": "1" }),
+            json!({ "unknown": 1 }),
+            json!({ "presetIndex": 99 }),
+            json!({ "nickname": 1 }),
+        ] {
+            assert!(settings.apply_patch(patch(invalid)).is_err());
+        }
+        assert!(settings.nickname.is_empty());
+    }
+
+    #[test]
+    fn picked_skin_wins() {
+        let mut settings = ModSettings::default();
+        settings
+            .apply_patch(patch(
+                json!({ "mainChar": 200075, "charSkin": { "200075": 407502 } }),
+            ))
+            .unwrap();
+        assert_eq!(settings.skin_of(200075), 407502);
+        assert_eq!(settings.skin_of(200002), 400201);
+    }
+}
@@ -0,0 +1,91 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hudsucker::{
+    Body,
+    hyper::{Request, Response, StatusCode, header},
+};
+use tracing::error;
+
+use crate::{cert::CaCert, settings::Settings};
+
+const SETUP_PAGE: &str = include_str!("./setup.html");
+
+/// Answers requests addressed to the proxy itself rather than forwarded through it,
+/// returns `None` for paths it does not serve
+pub fn serve(req: &Request<Body>, settings: &Settings, ca: &CaCert) -> Option<Response<Body>> {
+    // proxied requests carry an absolute URI, direct ones only a path
+    if req.uri().authority().is_some() {
+        return None;
+    }
+    let proxy = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        // the host ends up in the page and the PAC script, reject anything unusual
+        .filter(|host| {
+            host.chars()
+                .all(|c| c.is_ascii_alphanumeric() || ".:-[]".contains(c))
+        })
+        .unwrap_or(&settings.proxy_addr);
+
+    let (content_type, body) = match req.uri().path() {
+        "/" | "/setup" => (
+            "text/html; charset=utf-8",
+            Body::from(SETUP_PAGE.replace("{proxy}", proxy)),
+        ),
+        "/ca.pem" => ("application/x-pem-file", Body::from(ca.cert_pem.clone())),
+        "/ca.cer" | "/ca.der" => match ca.cert_der() {
+            Ok(der) => (
+                "application/x-x509-ca-cert",
+                Body::from(Full::new(Bytes::from(der))),
+            ),
+            Err(e) => {
+                error!("Failed to encode CA certificate: {e}");
+                return Some(
+                    Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::empty())
+                        .expect("Failed to build error response"),
+                );
+            }
+        },
+        "/proxy.pac" => (
+            "application/x-ns-proxy-autoconfig",
+            Body::from(pac(settings.intercept_hosts(), proxy)),
+        ),
+        _ => return None,
+    };
+    Some(
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(body)
+            .expect("Failed to build setup response"),
+    )
+}
+
+/// Proxy auto-config sending only hosts matching the intercept keywords through `proxy`
+fn pac(keywords: &[String], proxy: &str) -> String {
+    let condition = if keywords.is_empty() {
+        "true".to_string()
+    } else {
+        keywords
+            .iter()
+            .map(|keyword| {
+                // a JSON string is also a valid JavaScript string literal
+                let keyword = serde_json::to_string(keyword).expect("Failed to quote keyword");
+                format!("host.indexOf({keyword}) !== -1")
+            })
+            .collect::<Vec<_>>()
+            .join(" ||\n        ")
+    };
+    format!(
+        "function FindProxyForURL(url, host) {{
+    if ({condition}) {{
+        return \"PROXY {proxy}; DIRECT\";
+    }}
+    return \"DIRECT\";
+}}
+"
+    )
+}
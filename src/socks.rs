@@ -0,0 +1,142 @@
+use anyhow::{Context, Result, bail};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, lookup_host},
+};
+use tracing::{debug, error, info};
+
+use crate::upstream;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+
+const REPLY_SUCCEEDED: u8 = 0;
+const REPLY_FAILURE: u8 = 1;
+const REPLY_NOT_ALLOWED: u8 = 2;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// Accepts SOCKS5 clients on `listener` and hands every CONNECT to the HTTP proxy at `http_proxy`,
+/// so SOCKS5 connections are intercepted the same way as HTTP ones
+pub async fn serve(listener: TcpListener, http_proxy: SocketAddr) {
+    // a proxy listening on all interfaces is reached through loopback
+    let http_proxy = match http_proxy.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, http_proxy.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, http_proxy.port()).into(),
+        _ => http_proxy,
+    };
+    if let Ok(addr) = listener.local_addr() {
+        info!("SOCKS5代理已启动: {addr}");
+    }
+    loop {
+        let (stream, client) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept SOCKS5 connection: {e}");
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, client, http_proxy).await {
+                debug!("SOCKS5 connection from {client} closed: {e:#}");
+            }
+        });
+    }
+}
+
+async fn handle(
+    mut client: TcpStream,
+    client_addr: SocketAddr,
+    http_proxy: SocketAddr,
+) -> Result<()> {
+    client.set_nodelay(true)?;
+    let mut greeting = [0; 2];
+    client.read_exact(&mut greeting).await?;
+    if greeting[0] != VERSION {
+        bail!("Not a SOCKS5 client");
+    }
+    let mut methods = vec![0; greeting[1] as usize];
+    client.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTH) {
+        client.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        bail!("Client requires authentication");
+    }
+    client.write_all(&[VERSION, NO_AUTH]).await?;
+
+    let mut request = [0; 4];
+    client.read_exact(&mut request).await?;
+    let [_, command, _, address_type] = request;
+    let host = match address_type {
+        1 => {
+            let mut ip = [0; 4];
+            client.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut name = vec![0; client.read_u8().await? as usize];
+            client.read_exact(&mut name).await?;
+            String::from_utf8(name).context("Invalid host name")?
+        }
+        4 => {
+            let mut ip = [0; 16];
+            client.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        _ => {
+            reply(&mut client, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            bail!("Unsupported address type {address_type}");
+        }
+    };
+    let port = client.read_u16().await?;
+    if command != CMD_CONNECT {
+        reply(&mut client, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        bail!("Unsupported command {command}");
+    }
+    // the proxy sees every SOCKS5 client as local, keep remote ones away from local services
+    // such as the control API
+    if !client_addr.ip().to_canonical().is_loopback() && is_local(&host, port).await {
+        reply(&mut client, REPLY_NOT_ALLOWED).await?;
+        bail!("{client_addr} tried to reach local address {host}");
+    }
+
+    let mut server = match TcpStream::connect(http_proxy).await {
+        Ok(server) => server,
+        Err(e) => {
+            reply(&mut client, REPLY_FAILURE).await?;
+            return Err(e).context("Failed to connect to the HTTP proxy");
+        }
+    };
+    server.set_nodelay(true)?;
+    if let Err(e) = upstream::http_connect(&mut server, None, &host, port).await {
+        reply(&mut client, REPLY_FAILURE).await?;
+        return Err(e).with_context(|| format!("Failed to connect to {host}:{port}"));
+    }
+    reply(&mut client, REPLY_SUCCEEDED).await?;
+    tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+    Ok(())
+}
+
+async fn reply(client: &mut TcpStream, code: u8) -> std::io::Result<()> {
+    // the bound address is not meaningful here, report 0.0.0.0:0
+    client
+        .write_all(&[VERSION, code, 0, 1, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+/// Whether `host` is or resolves to a loopback or unspecified address, names such as
+/// `localhost.` or `127.1` included
+async fn is_local(host: &str, port: u16) -> bool {
+    // a name may be an IPv6 literal in brackets, which the proxy accepts as well
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match lookup_host((host, port)).await {
+        Ok(mut addrs) => addrs.any(|addr| {
+            let ip = addr.ip().to_canonical();
+            ip.is_loopback() || ip.is_unspecified()
+        }),
+        // the proxy cannot connect either
+        Err(_) => false,
+    }
+}
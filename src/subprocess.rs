@@ -0,0 +1,245 @@
+use anyhow::{Context, Result, bail};
+use serde_json::Value as JsonValue;
+use std::{path::PathBuf, process::Stdio, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdin, Command},
+    sync::{Mutex, Notify},
+    task::JoinHandle,
+    time::{Instant, sleep, timeout},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    delivery::{Delivery, Endpoint},
+    interceptor::BoxFuture,
+    settings::HelperTarget,
+};
+
+/// Longest a helper process may take to accept a payload on its stdin
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// A process running this long is considered healthy, its next restart is not delayed further
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// Runs a helper program and writes payloads to its stdin, one JSON object per line.
+///
+/// What the program prints is logged. A program that exits or stops reading is started
+/// again, backing off while it keeps exiting, and the current round is replayed to it.
+pub struct ProcessEndpoint {
+    process: Arc<Process>,
+    supervisor: JoinHandle<()>,
+}
+
+/// What the endpoint and the task supervising the program share
+#[derive(Default)]
+struct Process {
+    /// Stdin of the running program, `None` while it is down
+    stdin: Mutex<Option<ChildStdin>>,
+    /// Asks the supervisor to kill a program that stopped reading
+    kill: Notify,
+}
+
+/// How to start the program of a process target
+struct Launch {
+    label: String,
+    command: String,
+    args: Vec<String>,
+    dir: PathBuf,
+}
+
+impl ProcessEndpoint {
+    /// Starts the program, `delivery` replays the current round to it whenever it is restarted
+    pub fn start(target: &HelperTarget, dir: PathBuf, delivery: Arc<Delivery>) -> Result<Self> {
+        let launch = Launch {
+            label: target.label().to_string(),
+            command: target
+                .command
+                .clone()
+                .context("No command for process target")?,
+            args: target.args.clone(),
+            dir,
+        };
+        let process = Arc::new(Process::default());
+        Ok(Self {
+            process: process.clone(),
+            supervisor: tokio::spawn(launch.supervise(process, delivery)),
+        })
+    }
+}
+
+impl Drop for ProcessEndpoint {
+    fn drop(&mut self) {
+        // the program is killed along with the supervisor
+        self.supervisor.abort();
+    }
+}
+
+impl Endpoint for ProcessEndpoint {
+    fn send<'a>(&'a self, payload: &'a JsonValue) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut stdin = self.process.stdin.lock().await;
+            let Some(running) = stdin.as_mut() else {
+                bail!("助手进程未运行");
+            };
+            let mut line = payload.to_string();
+            line.push('\n');
+            let written = timeout(WRITE_TIMEOUT, async {
+                running.write_all(line.as_bytes()).await?;
+                running.flush().await
+            })
+            .await;
+            match written {
+                Ok(Ok(())) => Ok(()),
+                // it may have closed its stdin and kept running, the supervisor starts it again
+                Ok(Err(e)) => {
+                    *stdin = None;
+                    self.process.kill.notify_waiters();
+                    Err(e).context("无法写入助手进程")
+                }
+                Err(_) => {
+                    *stdin = None;
+                    // leaves no permit behind for the next program should this one have exited already
+                    self.process.kill.notify_waiters();
+                    bail!("助手进程未及时读取消息, 已终止")
+                }
+            }
+        })
+    }
+}
+
+impl Launch {
+    /// Keeps the program running until aborted
+    async fn supervise(self, process: Arc<Process>, delivery: Arc<Delivery>) {
+        let mut delay = MIN_RESTART_DELAY;
+        let mut restart = false;
+        loop {
+            match self.spawn() {
+                Ok((mut child, stdin)) => {
+                    // listening before stdin is handed out, a failed write may ask right away
+                    let kill = process.kill.notified();
+                    tokio::pin!(kill);
+                    kill.as_mut().enable();
+                    *process.stdin.lock().await = Some(stdin);
+                    if restart {
+                        // the new process has not seen the round so far
+                        delivery.resync();
+                    }
+                    let started = Instant::now();
+                    let status = tokio::select! {
+                        status = child.wait() => status,
+                        _ = kill => {
+                            let _ = child.start_kill();
+                            child.wait().await
+                        }
+                    };
+                    *process.stdin.lock().await = None;
+                    if started.elapsed() >= STABLE_RUN {
+                        delay = MIN_RESTART_DELAY;
+                    }
+                    match status {
+                        Ok(status) => warn!(
+                            "[{}] 助手进程已退出({status}), {}秒后重新启动",
+                            self.label,
+                            delay.as_secs()
+                        ),
+                        Err(e) => error!("[{}] Failed to wait for helper process: {e}", self.label),
+                    }
+                }
+                Err(e) => error!("[{}] {e:#}, {}秒后重试", self.label, delay.as_secs()),
+            }
+            restart = true;
+            sleep(delay).await;
+            delay = (delay * 2).min(MAX_RESTART_DELAY);
+        }
+    }
+
+    fn spawn(&self) -> Result<(Child, ChildStdin)> {
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .current_dir(&self.dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // stopped along with the target
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("无法启动助手进程{}", self.command))?;
+        let stdin = child.stdin.take().context("No stdin for helper process")?;
+        if let Some(stdout) = child.stdout.take() {
+            tokio::spawn(log_lines(self.label.clone(), stdout, false));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(log_lines(self.label.clone(), stderr, true));
+        }
+        info!("[{}] 助手进程已启动", self.label);
+        Ok((child, stdin))
+    }
+}
+
+async fn log_lines(label: String, output: impl AsyncRead + Unpin, stderr: bool) {
+    let mut lines = BufReader::new(output).lines();
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) if stderr => warn!("[{label}] {line}"),
+            Ok(Some(line)) => info!("[{label}] {line}"),
+            Ok(None) => break,
+            Err(e) => {
+                warn!("[{label}] 无法读取助手进程输出: {e}");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    fn starts(dir: &std::path::Path) -> usize {
+        fs::read_to_string(dir.join("starts")).map_or(0, |s| s.lines().count())
+    }
+
+    async fn wait_for_starts(dir: &std::path::Path, count: usize) {
+        timeout(Duration::from_secs(10), async {
+            while starts(dir) < count {
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("helper process not started");
+    }
+
+    #[tokio::test]
+    async fn restarts_program_that_closed_its_stdin() {
+        let dir =
+            std::env::temp_dir().join(format!("majsoul_max_rs-subprocess-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let _ = fs::remove_file(dir.join("starts"));
+        let target: HelperTarget = serde_json::from_value(json!({
+            "type": "process",
+            "command": "sh",
+            "args": ["-c", "echo >> starts; exec 0<&-; sleep 30"],
+        }))
+        .unwrap();
+        let endpoint =
+            ProcessEndpoint::start(&target, dir.clone(), Arc::new(Delivery::new("test".into())))
+                .unwrap();
+        wait_for_starts(&dir, 1).await;
+        // writes succeed until the program gets to close its stdin
+        timeout(Duration::from_secs(10), async {
+            while endpoint.send(&json!({})).await.is_ok() {
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("write to closed stdin succeeded");
+        // still running, so only the failed write gets it restarted
+        wait_for_starts(&dir, 2).await;
+        drop(endpoint);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
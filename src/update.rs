@@ -0,0 +1,67 @@
+use anyhow::Result;
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::RwLock, time::sleep};
+use tracing::{info, warn};
+
+use crate::{modder::Modder, session::Sessions, settings::Settings};
+
+/// How often to look at the settings again while background checks are off
+const IDLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Periodically checks for liqi and lqc.lqbin updates and applies them without a restart
+pub async fn update_worker(
+    settings: Arc<RwLock<Settings>>,
+    modder: Option<Arc<Modder>>,
+    sessions: Sessions,
+) {
+    loop {
+        // re-read every round, the interval can be changed while running
+        let Some(interval) = settings.read().await.update_check_interval() else {
+            sleep(IDLE_INTERVAL).await;
+            continue;
+        };
+        sleep(interval).await;
+
+        let (auto_update, mod_on) = {
+            let settings = settings.read().await;
+            (settings.auto_update(), settings.mod_on())
+        };
+        if auto_update && let Err(e) = update_liqi(&settings, &sessions).await {
+            warn!("更新liqi失败: {e:#}");
+        }
+        let Some(modder) = &modder else {
+            continue;
+        };
+        if mod_on
+            && modder.mod_settings().read().await.auto_update()
+            && let Err(e) = update_lqc(&settings, modder).await
+        {
+            warn!("更新mod失败: {e:#}");
+        }
+    }
+}
+
+/// Downloads a newer liqi if there is one and switches the helper and open sessions over to it
+pub async fn update_liqi(settings: &RwLock<Settings>, sessions: &Sessions) -> Result<bool> {
+    let mut updated = settings.read().await.clone();
+    if !updated.update().await? {
+        return Ok(false);
+    }
+    let desc = updated.desc.clone();
+    settings.write().await.apply_desc(updated);
+    sessions.set_pool(&desc).await;
+    info!("liqi更新成功, 已生效");
+    Ok(true)
+}
+
+/// Downloads a newer lqc.lqbin if there is one and swaps its tables into the mod
+pub async fn update_lqc(settings: &RwLock<Settings>, modder: &Modder) -> Result<bool> {
+    let source = settings.read().await.update_source().clone();
+    let mut updated = modder.mod_settings().read().await.clone();
+    if !updated.get_lqc(&source).await? {
+        return Ok(false);
+    }
+    modder.apply_lqc(&updated).await?;
+    info!("mod更新成功, 已生效");
+    Ok(true)
+}
@@ -0,0 +1,332 @@
+use anyhow::{Context, Result};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use hudsucker::{
+    Body,
+    hyper::{Request, Response, Uri, http::uri::Scheme},
+    hyper_util::rt::TokioIo,
+    rustls::{self, ClientConfig},
+    tokio_tungstenite::Connector,
+};
+use hyper_rustls::{ConfigBuilderExt, HttpsConnector, HttpsConnectorBuilder};
+use percent_encoding::percent_decode_str;
+use std::{
+    io::{Error, ErrorKind},
+    net::IpAddr,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, lookup_host},
+    sync::RwLock,
+};
+use tracing::{debug, error};
+use url::Url;
+
+use crate::{interceptor::BoxFuture, settings::Settings};
+
+/// Largest response to a CONNECT sent to an HTTP upstream that is accepted
+const MAX_CONNECT_RESPONSE: usize = 8 * 1024;
+
+/// Opens connections to servers, through the upstream proxy `upstreamProxy` picks for the host.
+///
+/// Used as the connector of the proxy's HTTP client, and by the handler for the tunnels and
+/// WebSockets hudsucker would otherwise open directly.
+#[derive(Clone)]
+pub struct Upstream {
+    settings: Arc<RwLock<Settings>>,
+    tls: Arc<ClientConfig>,
+}
+
+impl Upstream {
+    pub fn new(settings: Arc<RwLock<Settings>>) -> Result<Self> {
+        let tls = ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::aws_lc_rs::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .context("Failed to build TLS config")?
+        .with_webpki_roots()
+        .with_no_client_auth();
+        Ok(Self {
+            settings,
+            tls: Arc::new(tls),
+        })
+    }
+
+    /// HTTPS connector for the proxy's HTTP client
+    pub fn https_connector(&self) -> HttpsConnector<Upstream> {
+        HttpsConnectorBuilder::new()
+            .with_tls_config((*self.tls).clone())
+            .https_or_http()
+            .enable_http1()
+            .wrap_connector(self.clone())
+    }
+
+    /// TLS connector for WebSocket servers, sharing the roots of the HTTP client
+    pub fn websocket_connector(&self) -> Connector {
+        Connector::Rustls(self.tls.clone())
+    }
+
+    pub async fn proxy_for(&self, host: &str) -> Option<Url> {
+        self.settings.read().await.upstream_proxy(host).cloned()
+    }
+
+    pub async fn connect(&self, host: &str, port: u16) -> std::io::Result<TcpStream> {
+        let proxy = self.proxy_for(host).await;
+        connect_via(proxy.as_ref(), host, port).await
+    }
+
+    /// Answers a CONNECT request and tunnels it to its target through `proxy` without interception
+    pub fn tunnel(&self, mut req: Request<Body>, proxy: Url) -> Response<Body> {
+        let Some((host, port)) = host_port(req.uri()) else {
+            return Response::builder()
+                .status(400)
+                .body(Body::empty())
+                .expect("Failed to build response");
+        };
+        let upgrade = hudsucker::hyper::upgrade::on(&mut req);
+        tokio::spawn(async move {
+            let mut client = match upgrade.await {
+                Ok(upgraded) => TokioIo::new(upgraded),
+                Err(e) => {
+                    error!("Upgrade error: {e}");
+                    return;
+                }
+            };
+            let mut server = match connect_via(Some(&proxy), &host, port).await {
+                Ok(server) => server,
+                Err(e) => {
+                    error!("Failed to connect to {host}:{port} through {proxy}: {e}");
+                    return;
+                }
+            };
+            if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut server).await {
+                debug!("Tunnel to {host}:{port} closed: {e}");
+            }
+        });
+        Response::new(Body::empty())
+    }
+}
+
+impl tower_service::Service<Uri> for Upstream {
+    type Response = TokioIo<TcpStream>;
+    type Error = Error;
+    type Future = BoxFuture<'static, std::io::Result<TokioIo<TcpStream>>>;
+
+    fn poll_ready(&mut self, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let upstream = self.clone();
+        Box::pin(async move {
+            let (host, port) = host_port(&uri)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("No host in {uri}")))?;
+            upstream.connect(&host, port).await.map(TokioIo::new)
+        })
+    }
+}
+
+/// Host without IPv6 brackets and port of `uri`, the port defaulting to the scheme's
+pub fn host_port(uri: &Uri) -> Option<(String, u16)> {
+    let host = uri.host()?.trim_start_matches('[').trim_end_matches(']');
+    let port = uri.port_u16().unwrap_or(match uri.scheme() {
+        Some(scheme) if *scheme == Scheme::HTTP => 80,
+        Some(scheme) if scheme.as_str() == "ws" => 80,
+        _ => 443,
+    });
+    Some((host.to_string(), port))
+}
+
+/// Connects to `host:port`, through `proxy` if given
+pub async fn connect_via(proxy: Option<&Url>, host: &str, port: u16) -> std::io::Result<TcpStream> {
+    let Some(proxy) = proxy else {
+        let stream = TcpStream::connect((host, port)).await?;
+        stream.set_nodelay(true)?;
+        return Ok(stream);
+    };
+    let proxy_host = proxy
+        .host_str()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "No host in upstream proxy"))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let proxy_port = proxy.port_or_known_default().unwrap_or(1080);
+    let mut stream = TcpStream::connect((proxy_host, proxy_port)).await?;
+    stream.set_nodelay(true)?;
+    let credentials = credentials(proxy);
+    let credentials = credentials
+        .as_ref()
+        .map(|(username, password)| (username.as_slice(), password.as_slice()));
+    match proxy.scheme() {
+        "http" => http_connect(&mut stream, credentials, host, port).await?,
+        // socks5 resolves names here, socks5h leaves them to the proxy
+        "socks5" => {
+            let ip = match host.parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(_) => lookup_host((host, port))
+                    .await?
+                    .next()
+                    .ok_or_else(|| Error::new(ErrorKind::NotFound, "No address for host"))?
+                    .ip(),
+            };
+            socks5_connect(&mut stream, credentials, &ip.to_string(), port).await?
+        }
+        "socks5h" => socks5_connect(&mut stream, credentials, host, port).await?,
+        scheme => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("Unsupported upstream proxy scheme: {scheme}"),
+            ));
+        }
+    }
+    Ok(stream)
+}
+
+/// Percent-decoded user name and password of `proxy`, if it has a user name
+fn credentials(proxy: &Url) -> Option<(Vec<u8>, Vec<u8>)> {
+    (!proxy.username().is_empty()).then(|| {
+        (
+            percent_decode_str(proxy.username()).collect(),
+            percent_decode_str(proxy.password().unwrap_or("")).collect(),
+        )
+    })
+}
+
+/// Asks the HTTP proxy at the other end of `stream` to CONNECT to `host:port`
+pub async fn http_connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    credentials: Option<(&[u8], &[u8])>,
+    host: &str,
+    port: u16,
+) -> std::io::Result<()> {
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{host}]:{port}"),
+        _ => format!("{host}:{port}"),
+    };
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some((username, password)) = credentials {
+        request += &format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            BASE64_STANDARD.encode([username, b":", password].concat())
+        );
+    }
+    request += "\r\n";
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte, nothing after the header may be consumed
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_CONNECT_RESPONSE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Upstream proxy response too large",
+            ));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("Upstream proxy refused CONNECT: {status_line}"),
+        )),
+    }
+}
+
+async fn socks5_connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    credentials: Option<(&[u8], &[u8])>,
+    host: &str,
+    port: u16,
+) -> std::io::Result<()> {
+    if credentials.is_some() {
+        stream.write_all(&[5, 2, 0, 2]).await?;
+    } else {
+        stream.write_all(&[5, 1, 0]).await?;
+    }
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    match choice {
+        [5, 0] => {}
+        [5, 2] if let Some((username, password)) = credentials => {
+            // username / password authentication, RFC 1929
+            let too_long = |_| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "SOCKS5 user name or password too long",
+                )
+            };
+            let mut request = vec![1, u8::try_from(username.len()).map_err(too_long)?];
+            request.extend_from_slice(username);
+            request.push(u8::try_from(password.len()).map_err(too_long)?);
+            request.extend_from_slice(password);
+            stream.write_all(&request).await?;
+            let mut status = [0; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "Upstream SOCKS5 proxy rejected the credentials",
+                ));
+            }
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                "Upstream SOCKS5 proxy accepts no supported authentication method",
+            ));
+        }
+    }
+
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        // the proxy resolves names
+        Err(_) => {
+            let host = host.as_bytes();
+            let len = u8::try_from(host.len())
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "Host name too long"))?;
+            request.push(3);
+            request.push(len);
+            request.extend_from_slice(host);
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!(
+                "Upstream SOCKS5 proxy failed to connect, reply {}",
+                reply[1]
+            ),
+        ));
+    }
+    // skip the bound address
+    let len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid SOCKS5 address type",
+            ));
+        }
+    };
+    let mut bound = vec![0; len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}